@@ -1,123 +1,312 @@
 use crate::{
-    display,
-    structs::{Battle, BattleStat, Character, Match, MatchResult},
+    glicko::expected_deviation,
+    glicko::record_match,
+    matchmaker::Matchmaker,
+    structs::{index_by_id, Character, History, Match, MatchResult, Rank},
 };
-use rand::{distributions::WeightedIndex, prelude::*};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
-    io::{self, Write},
+    collections::HashMap,
+    io::{BufRead, Write},
 };
 
-// The maximum number of recent battles
-const MAX_HIST: usize = 5;
-
-fn update_tmp_history(characters: &mut Vec<Character>, a: usize, b: usize) {
-    // characters[m.a].hist.battles() += 1
-    characters[a].hist.draw += 1;
-    characters[b].hist.draw += 1;
-
-    let name_a = characters[a].name.clone();
-    let name_b = characters[b].name.clone();
-    characters[a]
-        .hist
-        .recent
-        .push_back(Battle::new(name_b, MatchResult::Draw));
-    characters[b]
-        .hist
-        .recent
-        .push_back(Battle::new(name_a, MatchResult::Draw));
-    while characters[a].hist.recent.len() > MAX_HIST {
-        characters[a].hist.recent.pop_front();
+pub enum BattleStat {
+    Next,
+    End,
+    Undo,
+    Redo,
+    Skip(Unknown),
+}
+
+// What the user does not know when skipping a pair
+pub enum Unknown {
+    Nothing, // only skip the pair in this session
+    Pair,
+    Left,
+    Right,
+}
+
+// A session in progress, kept after every pick so it can be resumed
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub matchmaker: String, // the name of the matchmaker picking the pairs
+    // the characters with the temporary history of the session
+    pub pool: Vec<Character>,
+    pub records: Vec<Match>,
+    // the states of both sides before each battle, to undo it exactly
+    saved: Vec<[(Rank, History); 2]>,
+    // the undone battles with the pair which was pending after them, latest last
+    #[serde(default)]
+    redo: Vec<(Match, (usize, usize))>,
+    // the positions in pool of the pending pair, None before the first pick
+    pub pair: Option<(usize, usize)>,
+    // the ids of the characters and pairs marked as unknown to the user
+    #[serde(default)]
+    pub unknown_charas: Vec<usize>,
+    #[serde(default)]
+    pub unknown_pairs: Vec<(usize, usize)>,
+}
+
+impl Session {
+    pub fn new(pool: &[Character], matchmaker: &str) -> Self {
+        Session {
+            matchmaker: matchmaker.to_string(),
+            pool: pool.to_vec(),
+            records: Vec::new(),
+            saved: Vec::new(),
+            redo: Vec::new(),
+            pair: None,
+            unknown_charas: Vec::new(),
+            unknown_pairs: Vec::new(),
+        }
     }
-    while characters[b].hist.recent.len() > MAX_HIST {
-        characters[b].hist.recent.pop_front();
+
+    // Move the session onto the current characters, matched by id, so it can
+    // resume after characters were added, removed or reordered. Returns None
+    // if a character of a battle or of the pending pair no longer exists
+    pub fn remap(mut self, characters: &[Character]) -> Option<Session> {
+        let index = index_by_id(characters);
+        let known = |id: &usize| index.contains_key(id);
+        if !self.records.iter().all(|m| known(&m.a) && known(&m.b)) {
+            return None;
+        }
+        let old_index = index_by_id(&self.pool);
+        let old_ids: Vec<usize> = self.pool.iter().map(|c| c.id).collect();
+        let position = |p: usize| index.get(&old_ids[p]).copied();
+        let move_pair = |(a, b): (usize, usize)| Some((position(a)?, position(b)?));
+        let pair = match self.pair {
+            Some(pair) => Some(move_pair(pair)?),
+            None => None,
+        };
+
+        // Keep the temporary history and the skips of the session
+        let pool = characters
+            .iter()
+            .map(|c| {
+                let mut c = c.clone();
+                if let Some(&i) = old_index.get(&c.id) {
+                    let old = &self.pool[i];
+                    c.rank = old.rank.clone();
+                    c.hist = old.hist.clone();
+                    c.unknown |= old.unknown;
+                    for id in old.avoid.iter() {
+                        if !c.avoid.contains(id) {
+                            c.avoid.push(*id);
+                        }
+                    }
+                }
+                c
+            })
+            .collect();
+        self.redo = std::mem::take(&mut self.redo)
+            .into_iter()
+            .filter(|(m, _)| known(&m.a) && known(&m.b))
+            .filter_map(|(m, pending)| Some((m, move_pair(pending)?)))
+            .collect();
+        self.pool = pool;
+        self.pair = pair;
+        Some(self)
     }
-}
 
-fn pick_2_player_ids(pool: &[Character], name_to_id: &HashMap<String, usize>) -> (usize, usize) {
-    let max = pool
-        .iter()
-        .max_by_key(|c| c.hist.battles())
-        .unwrap()
-        .hist
-        .battles();
-
-    // Calculate the weights (inverse of the battles)
-    let weights: Vec<_> = pool
-        .iter()
-        .map(|c: &Character| {
-            if max == c.hist.battles() {
-                return 0.0;
-            } else {
-                return (1 << (2 * (max - c.hist.battles()))) as f64;
+    // Keep the matchmaker away from a skipped pair, for the rest of the
+    // session or (as recorded in the unknown lists) for good
+    fn skip(&mut self, left: usize, right: usize, unknown: Unknown) {
+        let (id_left, id_right) = (self.pool[left].id, self.pool[right].id);
+        match unknown {
+            Unknown::Left => {
+                self.pool[left].unknown = true;
+                self.unknown_charas.push(id_left);
             }
-        })
-        .collect();
-
-    // Create a weighted index distribution
-    let distribution = WeightedIndex::new(&weights).unwrap();
-    let mut rng = thread_rng();
+            Unknown::Right => {
+                self.pool[right].unknown = true;
+                self.unknown_charas.push(id_right);
+            }
+            Unknown::Pair | Unknown::Nothing => {
+                self.pool[left].avoid.push(id_right);
+                if let Unknown::Pair = unknown {
+                    self.unknown_pairs.push((id_left, id_right));
+                }
+            }
+        }
+    }
 
-    // Select two distinct indices
-    let first_index = distribution.sample(&mut rng);
+    // Apply the marks of the session to the characters,
+    // returns whether anything was marked
+    pub fn mark_unknown(&self, characters: &mut [Character]) -> bool {
+        let index = index_by_id(characters);
+        for id in self.unknown_charas.iter() {
+            if let Some(&i) = index.get(id) {
+                characters[i].unknown = true;
+            }
+        }
+        for (a, b) in self.unknown_pairs.iter() {
+            if let Some(&i) = index.get(a) {
+                if !characters[i].avoid.contains(b) {
+                    characters[i].avoid.push(*b);
+                }
+            }
+        }
+        !self.unknown_charas.is_empty() || !self.unknown_pairs.is_empty()
+    }
 
-    // find recent opponents of first selected
-    let mut oppos: HashSet<usize> = HashSet::new();
-    for b in pool[first_index].hist.recent.iter() {
-        oppos.insert(name_to_id[&b.oppo]);
+    // Count a battle in the temporary history, as update_history will do
+    // after the session, so matchmakers see the real outcomes
+    fn play(&mut self, m: Match) {
+        let index = index_by_id(&self.pool);
+        let (a, b) = (index[&m.a], index[&m.b]);
+        let state = |c: &Character| (c.rank.clone(), c.hist.clone());
+        self.saved
+            .push([state(&self.pool[a]), state(&self.pool[b])]);
+        record_match(&mut self.pool, a, b, &m.res);
+        update_tmp_deviation(&mut self.pool, a, b);
+        self.records.push(m);
     }
 
-    let mut second_index = distribution.sample(&mut rng);
-    while second_index == first_index || oppos.contains(&second_index) {
-        second_index = distribution.sample(&mut rng);
+    // Take back the last battle, returns the positions of its pair
+    fn undo(&mut self) -> (usize, usize) {
+        let m = self.records.pop().unwrap();
+        let index = index_by_id(&self.pool);
+        let (a, b) = (index[&m.a], index[&m.b]);
+        let [state_a, state_b] = self.saved.pop().unwrap();
+        (self.pool[a].rank, self.pool[a].hist) = state_a;
+        (self.pool[b].rank, self.pool[b].hist) = state_b;
+        if let Some(pending) = self.pair {
+            self.redo.push((m, pending));
+        }
+        (a, b)
     }
+}
+
+// Treat a battle of the session as played for matchmakers using deviations,
+// so they move on to other pairs although ratings only change after the session
+fn update_tmp_deviation(characters: &mut [Character], a: usize, b: usize) {
+    let devi_a = expected_deviation(&characters[a].rank, &characters[b].rank);
+    let devi_b = expected_deviation(&characters[b].rank, &characters[a].rank);
+    characters[a].rank.devi = devi_a;
+    characters[b].rank.devi = devi_b;
+}
+
+// Messages of the session go to the transcript, a failed write only loses
+// the transcript and never the entered results
+fn start_session(out: &mut dyn Write, num_characters: usize) {
+    let _ = writeln!(
+        out,
+        "=== Starting a new session with {} characters ===",
+        num_characters
+    );
+}
+
+fn start_fight(out: &mut dyn Write, battle_id: usize, left: &str, right: &str) {
+    let _ = writeln!(out, "-----------------------------");
+    let _ = writeln!(out, "Battle #{}: {} vs {}", battle_id + 1, left, right);
+    let _ = write!(out, "Pick [ 'h' for help ] >> ");
+    let _ = out.flush();
+}
+
+fn fight_help(out: &mut dyn Write) {
+    let _ = writeln!(out, "11/22 to choose left/right");
+    let _ = writeln!(out, "1/2 if you only slightly prefer left/right");
+    let _ = writeln!(out, "0 for draws");
+    let _ = writeln!(out, "d if you DISLIKE BOTH of them");
+    let _ = writeln!(out, "u to UNDO, r to REDO what was undone");
+    let _ = writeln!(out, "s to SKIP this pair, sp to never pick it again");
+    let _ = writeln!(out, "s1/s2 to never pick left/right again");
+    let _ = writeln!(out, "<Enter> to end this session");
+}
+
+fn resume_session(out: &mut dyn Write, num_battles: usize) {
+    let _ = writeln!(
+        out,
+        "=== Resuming a session after {} battles ===",
+        num_battles
+    );
+}
 
-    (first_index, second_index)
+fn not_enough_characters(out: &mut dyn Write) {
+    let _ = writeln!(out, "Not enough characters can battle, end this session.");
 }
 
-fn fight(battle_id: usize, left: &str, right: &str) -> (MatchResult, BattleStat) {
+fn fight(
+    battle_id: usize,
+    can_redo: bool,
+    left: &str,
+    right: &str,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+) -> (MatchResult, BattleStat) {
     let mut choice: String = String::new();
     loop {
-        display::start_fight(battle_id, left, right);
-        let _ = io::stdout().flush();
+        start_fight(out, battle_id, left, right);
         choice.clear();
 
-        let _ = io::stdin().read_line(&mut choice);
+        // The end of the input ends the session like an empty line
+        let _ = input.read_line(&mut choice);
 
         let mut res = MatchResult::Draw;
 
         choice = choice.trim().to_string();
-        if choice.starts_with('1') {
+        if choice.starts_with("11") {
             // I like left
             res = MatchResult::AWin;
-            display::fight_result(left);
-        } else if choice.starts_with('2') {
+            let _ = writeln!(out, "Chose - {}!", left);
+        } else if choice.starts_with('1') {
+            // I like left a little more
+            res = MatchResult::AWeakWin;
+            let _ = writeln!(out, "Chose - {} (slightly)!", left);
+        } else if choice.starts_with("22") {
             // I like right
             res = MatchResult::BWin;
-            display::fight_result(right);
+            let _ = writeln!(out, "Chose - {}!", right);
+        } else if choice.starts_with('2') {
+            // I like right a little more
+            res = MatchResult::BWeakWin;
+            let _ = writeln!(out, "Chose - {} (slightly)!", right);
         } else if choice.starts_with("0") {
             // Draw
-            display::fight_result("Draw!");
+            let _ = writeln!(out, "Chose - Draw!!");
             res = MatchResult::Draw;
         } else if choice.starts_with('d') {
             // I dislike them both!
             res = MatchResult::BothLose;
-            display::fight_dislike_both();
+            let _ = writeln!(out, "Disliked both!");
         } else if choice.starts_with('u') {
             // Undo
             if battle_id == 0 {
-                display::fight_undo_err();
+                let _ = writeln!(out, "This is the first battle!");
                 continue;
             }
-            display::fight_undo();
+            let _ = writeln!(out, "Undoing...");
             return (res, BattleStat::Undo);
+        } else if choice.starts_with('r') {
+            // Redo
+            if !can_redo {
+                let _ = writeln!(out, "Nothing to redo!");
+                continue;
+            }
+            let _ = writeln!(out, "Redoing...");
+            return (res, BattleStat::Redo);
+        } else if let Some(mark) = choice.strip_prefix('s') {
+            // Skip, the pair or a character may be unknown to me
+            let (unknown, text) = match mark {
+                "1" => (Unknown::Left, left),
+                "2" => (Unknown::Right, right),
+                "p" => (Unknown::Pair, "this pair"),
+                _ => (Unknown::Nothing, "this pair"),
+            };
+            if let Unknown::Nothing = unknown {
+                let _ = writeln!(out, "Skipped!");
+            } else {
+                let _ = writeln!(out, "Skipped, {} will not be picked again!", text);
+            }
+            return (res, BattleStat::Skip(unknown));
         } else if choice.starts_with('h') {
             // Help
-            display::fight_help();
+            fight_help(out);
             continue;
         } else {
             // End
-            display::fight_end();
+            let _ = writeln!(out, "Finish rating session.");
             return (res, BattleStat::End);
         }
 
@@ -125,40 +314,85 @@ fn fight(battle_id: usize, left: &str, right: &str) -> (MatchResult, BattleStat)
     }
 }
 
-pub fn battles(pool: &[Character], name_to_id: &HashMap<String, usize>) -> Vec<Match> {
-    display::start_session(pool.len());
-
-    let mut _pool = pool.to_vec();
-    let mut records: Vec<Match> = Vec::new();
-
-    let (mut left, mut right) = pick_2_player_ids(&_pool, name_to_id);
+// Run a session reading the choices from input and writing the transcript
+// to out, returns the finished session. journal is given the session
+// whenever a new pair is pending, so it can be resumed after a crash
+pub fn battles(
+    mut session: Session,
+    name_to_id: &HashMap<String, usize>,
+    matchmaker: &mut dyn Matchmaker,
+    rng: &mut dyn RngCore,
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+    journal: &mut dyn FnMut(&Session),
+) -> Session {
+    if session.pair.is_some() {
+        resume_session(out, session.records.len());
+    } else {
+        start_session(out, session.pool.len());
+        session.pair = matchmaker.pick(&session.pool, name_to_id, rng);
+    }
+    let Some(mut pair) = session.pair else {
+        not_enough_characters(out);
+        return session;
+    };
+    journal(&session);
 
     loop {
+        let (left, right) = pair;
         let (res, stat) = fight(
-            records.len(),
-            pool[left].name.as_str(),
-            pool[right].name.as_str(),
+            session.records.len(),
+            !session.redo.is_empty(),
+            session.pool[left].label(),
+            session.pool[right].label(),
+            input,
+            out,
         );
 
         match stat {
             BattleStat::Next => {
-                records.push(Match::new(left, right, res));
-                update_tmp_history(&mut _pool, left, right);
-                (left, right) = pick_2_player_ids(&_pool, name_to_id);
+                let (id_a, id_b) = (session.pool[left].id, session.pool[right].id);
+                // A new result makes the undone battles obsolete
+                session.redo.clear();
+                session.play(Match::new(id_a, id_b, res));
+                session.pair = matchmaker.pick(&session.pool, name_to_id, rng);
+                match session.pair {
+                    Some(next) => pair = next,
+                    None => {
+                        not_enough_characters(out);
+                        break;
+                    }
+                }
             }
             BattleStat::End => {
                 break;
             }
             BattleStat::Undo => {
-                let last = records.last().unwrap();
-                left = last.a;
-                right = last.b;
-                records.pop();
-                _pool[left].hist.recent.pop_back();
-                _pool[right].hist.recent.pop_back();
+                pair = session.undo();
+                session.pair = Some(pair);
+            }
+            BattleStat::Skip(unknown) => {
+                // The undone battles could bring back what was just marked
+                session.redo.clear();
+                session.skip(left, right, unknown);
+                session.pair = matchmaker.pick(&session.pool, name_to_id, rng);
+                match session.pair {
+                    Some(next) => pair = next,
+                    None => {
+                        not_enough_characters(out);
+                        break;
+                    }
+                }
+            }
+            BattleStat::Redo => {
+                let (m, next) = session.redo.pop().unwrap();
+                session.play(m);
+                pair = next;
+                session.pair = Some(pair);
             }
         }
+        journal(&session);
     }
 
-    records
+    session
 }
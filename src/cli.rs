@@ -0,0 +1,259 @@
+use crate::{display, storage::Pool};
+use glicko2::{config::GlickoConfig, matchmaker::MATCHMAKERS, structs::MatchResult};
+use std::{io, process::exit};
+
+// Exit code for invalid command line arguments
+pub const EXIT_USAGE: i32 = 2;
+// Exit code for valid arguments that cannot be carried out
+pub const EXIT_FAILURE: i32 = 1;
+
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+pub enum Command {
+    // The interactive lobby (default)
+    Lobby,
+    List {
+        top: Option<usize>,
+        tags: Vec<String>,
+    },
+    Stat {
+        chara: String,
+    },
+    // Record the result of a single match as one rating period
+    Record {
+        a: String,
+        b: String,
+        res: MatchResult,
+    },
+    // Run one session with the choices read from a file ('-' for stdin)
+    Play {
+        script: String,
+    },
+    // Rebuild all ratings from the match log
+    Replay,
+    Rename {
+        chara: String,
+        name: String,
+    },
+    Alias {
+        chara: String,
+        alias: String,
+    },
+    // Exclude (or include again) characters from battles
+    Exclude {
+        charas: Vec<String>,
+        excluded: bool,
+    },
+    // Take back an unknown mark of a character or of a pair
+    Known {
+        chara: String,
+        other: Option<String>,
+    },
+    Export {
+        top: Option<usize>,
+        format: ExportFormat,
+    },
+}
+
+pub struct Options {
+    pub pool: Pool,
+    pub config: GlickoConfig,
+    // the default matchmaker of lobby sessions
+    pub matchmaker: String,
+    // seeds the pairings of lobby sessions, random if None
+    pub seed: Option<u64>,
+    pub command: Command,
+}
+
+// Parse the command line, exit with usage on errors
+pub fn parse_args(args: &[String]) -> Options {
+    match try_parse_args(args) {
+        Ok(opts) => opts,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            display::usage(&mut io::stderr());
+            exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn try_parse_args(args: &[String]) -> Result<Options, String> {
+    let mut pool_arg: Option<String> = None;
+    let mut config_path: Option<String> = None;
+    let mut overrides: Vec<(String, String)> = Vec::new();
+    let mut positional: Vec<String> = Vec::new();
+    let mut top: Option<usize> = None;
+    let mut tags: Vec<String> = Vec::new();
+    let mut matchmaker = MATCHMAKERS[0].to_string();
+    let mut seed: Option<u64> = None;
+    let mut format = ExportFormat::Csv;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let Some(key) = arg.strip_prefix("--") else {
+            positional.push(arg.clone());
+            continue;
+        };
+        if key == "help" {
+            display::usage(&mut io::stdout());
+            exit(0);
+        }
+        let value = iter
+            .next()
+            .ok_or_else(|| format!("missing value for --{}", key))?;
+        match key {
+            "pool" => pool_arg = Some(value.clone()),
+            "config" => config_path = Some(value.clone()),
+            "top" => {
+                let n = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for top: {}", value))?;
+                top = Some(n);
+            }
+            "tag" => tags.push(value.clone()),
+            "matchmaker" => {
+                if !MATCHMAKERS.contains(&value.as_str()) {
+                    return Err(format!("unknown matchmaker: {}", value));
+                }
+                matchmaker = value.clone();
+            }
+            "seed" => {
+                let n = value
+                    .parse()
+                    .map_err(|_| format!("invalid value for seed: {}", value))?;
+                seed = Some(n);
+            }
+            "format" => {
+                format = match value.as_str() {
+                    "csv" => ExportFormat::Csv,
+                    "json" => ExportFormat::Json,
+                    _ => return Err(format!("unknown export format: {}", value)),
+                }
+            }
+            _ => overrides.push((key.to_string(), value.clone())),
+        }
+    }
+
+    let pool = Pool::resolve(pool_arg.as_deref())?;
+
+    // The config file of the pool is optional unless given explicitly
+    let mut config = match config_path {
+        Some(path) => GlickoConfig::load(path)?,
+        None if pool.config_path().exists() => GlickoConfig::load(pool.config_path())?,
+        None => GlickoConfig::default(),
+    };
+    // Command line flags take precedence over the config file
+    for (key, value) in overrides.iter() {
+        config.set(key, value)?;
+    }
+
+    let command = parse_command(&positional, top, tags, format)?;
+    Ok(Options {
+        pool,
+        config,
+        matchmaker,
+        seed,
+        command,
+    })
+}
+
+fn parse_command(
+    positional: &[String],
+    top: Option<usize>,
+    tags: Vec<String>,
+    format: ExportFormat,
+) -> Result<Command, String> {
+    let Some((name, rest)) = positional.split_first() else {
+        return Ok(Command::Lobby);
+    };
+    let expect_args = |n: usize| {
+        if rest.len() == n {
+            Ok(())
+        } else {
+            Err(format!(
+                "'{}' takes {} argument(s), got {}",
+                name,
+                n,
+                rest.len()
+            ))
+        }
+    };
+    match name.as_str() {
+        "lobby" => {
+            expect_args(0)?;
+            Ok(Command::Lobby)
+        }
+        "list" => {
+            expect_args(0)?;
+            Ok(Command::List { top, tags })
+        }
+        "stat" => {
+            expect_args(1)?;
+            Ok(Command::Stat {
+                chara: rest[0].clone(),
+            })
+        }
+        "record" => {
+            expect_args(3)?;
+            Ok(Command::Record {
+                a: rest[0].clone(),
+                b: rest[1].clone(),
+                res: rest[2].parse()?,
+            })
+        }
+        "play" => {
+            expect_args(1)?;
+            Ok(Command::Play {
+                script: rest[0].clone(),
+            })
+        }
+        "replay" => {
+            expect_args(0)?;
+            Ok(Command::Replay)
+        }
+        "rename" => {
+            expect_args(2)?;
+            Ok(Command::Rename {
+                chara: rest[0].clone(),
+                name: rest[1].clone(),
+            })
+        }
+        "exclude" | "include" => {
+            if rest.is_empty() {
+                return Err(format!("'{}' takes at least 1 argument", name));
+            }
+            Ok(Command::Exclude {
+                charas: rest.to_vec(),
+                excluded: name == "exclude",
+            })
+        }
+        "known" => {
+            if rest.is_empty() || rest.len() > 2 {
+                return Err(format!(
+                    "'{}' takes 1 or 2 arguments, got {}",
+                    name,
+                    rest.len()
+                ));
+            }
+            Ok(Command::Known {
+                chara: rest[0].clone(),
+                other: rest.get(1).cloned(),
+            })
+        }
+        "alias" => {
+            expect_args(2)?;
+            Ok(Command::Alias {
+                chara: rest[0].clone(),
+                alias: rest[1].clone(),
+            })
+        }
+        "export" => {
+            expect_args(0)?;
+            Ok(Command::Export { top, format })
+        }
+        _ => Err(format!("unknown command: {}", name)),
+    }
+}
@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+// The scale factor between the Glicko-1 and Glicko-2 scales
+pub const GLICKO2_SCALE: f64 = 173.7178;
+// The Glicko-1 rating which maps to 0 on the Glicko-2 scale
+pub const GLICKO2_CENTER: f64 = 1500.0;
+
+// The system parameters of a pool, every field falls back to the
+// values suggested by the paper when it is missing from the config file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GlickoConfig {
+    // The system constant which constrains the change in volatility over time
+    // Reasonable choices are between 0.3 and 1.2
+    pub tau: f64,
+    // Convergence tolerance of the volatility iteration
+    pub epsilon: f64,
+    // Rating, deviation and volatility of new characters (Glicko-1 scale)
+    pub init_rati: f64,
+    pub init_devi: f64,
+    pub init_vola: f64,
+    // Increase the deviation of characters who did not play in a rating period
+    pub inflate_idle: bool,
+    // Length of a rating period in seconds, the deviation grows with the
+    // (fractional) number of periods since the last update
+    // 0 treats every session as exactly one rating period
+    pub period_len: u64,
+    // The score of a slight win (the loser scores the rest), 1 is a full win
+    pub weak_win: f64,
+}
+
+impl GlickoConfig {
+    pub fn new() -> Self {
+        Self {
+            tau: (0.5),
+            epsilon: (1e-6),
+            init_rati: (1500.0),
+            init_devi: (350.0),
+            init_vola: (0.06),
+            inflate_idle: (true),
+            period_len: (0),
+            weak_win: (0.75),
+        }
+    }
+
+    // Read a config from a json file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+        let config: Self = serde_json::from_str(&content)
+            .map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Set a single field by name, used for command line overrides
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let invalid = || format!("invalid value for {}: {}", key, value);
+        let number = || value.parse::<f64>().map_err(|_| invalid());
+        match key {
+            "tau" => self.tau = number()?,
+            "epsilon" => self.epsilon = number()?,
+            "init-rating" => self.init_rati = number()?,
+            "init-deviation" => self.init_devi = number()?,
+            "init-volatility" => self.init_vola = number()?,
+            "inflate-idle" => self.inflate_idle = value.parse().map_err(|_| invalid())?,
+            "period-length" => self.period_len = value.parse().map_err(|_| invalid())?,
+            "weak-win" => self.weak_win = number()?,
+            _ => return Err(format!("unknown option: --{}", key)),
+        }
+        self.validate()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let positive = [
+            ("tau", self.tau),
+            ("epsilon", self.epsilon),
+            ("init-deviation", self.init_devi),
+            ("init-volatility", self.init_vola),
+        ];
+        for (key, value) in positive {
+            if !(value > 0.0 && value.is_finite()) {
+                return Err(format!("{} must be a positive number, got {}", key, value));
+            }
+        }
+        if !self.init_rati.is_finite() {
+            return Err(format!(
+                "init-rating must be finite, got {}",
+                self.init_rati
+            ));
+        }
+        if !(0.5..=1.0).contains(&self.weak_win) {
+            return Err(format!(
+                "weak-win must be between 0.5 and 1, got {}",
+                self.weak_win
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for GlickoConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,17 +1,29 @@
-use crate::structs::{Character, MatchResult};
-use std::collections::HashMap;
+use crate::storage::POOL_ENV;
+use glicko2::{
+    glicko::calculate_tag_ranking,
+    matchmaker::MATCHMAKERS,
+    structs::{Character, LogEntry, MatchResult, Snapshot},
+};
+use serde_json::json;
+use std::{collections::HashMap, io::Write};
 
 fn print_rank_entry(c: &Character, rank: usize, tab: usize) {
     print!("{:<1$}", "", tab);
     println!(
-        "{:<4} {:<26}{}",
+        "{:<4} {:<26}({: <7} ± {:.0}){}",
         format!("{}.", rank),
-        c.name,
-        format!(
-            "({0: <7} ± {1:.0})",
-            format!("{:.2}", c.rank.rati),
-            c.rank.devi
-        )
+        c.label(),
+        format!("{:.2}", c.rank.rati),
+        c.rank.devi,
+        if !c.meta.active {
+            "  inactive"
+        } else if c.excluded {
+            "  excluded"
+        } else if c.unknown {
+            "  unknown"
+        } else {
+            ""
+        }
     );
 }
 
@@ -19,22 +31,13 @@ fn get_slice_in_ranked_chara<'a>(
     character: &'a Character,
     ranked_chara: &'a [Character],
 ) -> Vec<&'a Character> {
-    for (i, c) in ranked_chara.iter().enumerate() {
-        if c.id != character.id {
-            continue;
-        }
-        if i == 0 {
-            // top
-            return vec![&ranked_chara[0], &ranked_chara[1], &ranked_chara[2]];
-        } else if i == ranked_chara.len() - 1 {
-            // bottom
-            return vec![&ranked_chara[i - 2], &ranked_chara[i - 1], &ranked_chara[i]];
-        } else {
-            // middle
-            return vec![&ranked_chara[i - 1], &ranked_chara[i], &ranked_chara[i + 1]];
-        }
-    }
-    vec![]
+    let Some(i) = ranked_chara.iter().position(|c| c.id == character.id) else {
+        return vec![];
+    };
+    // The character and its neighbours, shifted inwards at the top and bottom
+    let len = ranked_chara.len().min(3);
+    let start = i.saturating_sub(1).min(ranked_chara.len() - len);
+    ranked_chara[start..start + len].iter().collect()
 }
 
 pub fn stat(
@@ -47,20 +50,42 @@ pub fn stat(
     println!("{:-<1$}", "", 58);
     println!(
         "{0: <45}{1: >13}",
-        format!("~~ {} ~~", chara.name),
+        format!("~~ {} ~~", chara.label()),
         format!("Rank #{}/{}", ranks[&chara.id], ranked_chara.len())
     );
+    if !chara.aliases.is_empty() {
+        println!("aka {}", chara.aliases.join(", "));
+    }
+    if chara.meta.display.is_some() {
+        println!("name: {}", chara.name);
+    }
+    if !chara.meta.tags.is_empty() {
+        println!("tags: {}", chara.meta.tags.join(", "));
+    }
+    if let Some(image) = &chara.meta.image {
+        println!("image: {}", image);
+    }
+    if !chara.meta.active {
+        println!("(inactive)");
+    } else if chara.excluded {
+        println!("(excluded from battles)");
+    } else if chara.unknown {
+        println!("(marked unknown, never picked for battles)");
+    }
+    let unknown_against: Vec<&str> = characters
+        .iter()
+        .filter(|c| c.id != chara.id && chara.avoids(c))
+        .map(|c| c.label())
+        .collect();
+    if !unknown_against.is_empty() {
+        println!("unknown against: {}", unknown_against.join(", "));
+    }
     println!("{:-<1$}", "", 58);
 
-    println!("==> {}", "RATING");
+    println!("==> RATING");
     println!(
-        "{}",
-        format!(
-            "    {} ± {1:.0} | (volatility: {2:.6})",
-            format!("{:.2}", chara.rank.rati),
-            chara.rank.devi,
-            chara.rank.vola
-        )
+        "    {:.2} ± {:.0} | (volatility: {:.6})",
+        chara.rank.rati, chara.rank.devi, chara.rank.vola
     );
     if chara.rank.devi > 160.0 {
         println!("    ⓘ The uncertainty is high, do more battles!\n");
@@ -112,7 +137,7 @@ pub fn stat(
     }
 
     // Rank informations
-    println!("\n==> {}", "RANKINGS");
+    println!("\n==> RANKINGS");
     // Overall ranks
     let slice = get_slice_in_ranked_chara(chara, ranked_chara);
     println!(
@@ -124,25 +149,34 @@ pub fn stat(
     for c in slice.iter() {
         print_rank_entry(c, ranks[&c.id], 4);
     }
+    // Ranks among the characters sharing each tag
+    for tag in chara.meta.tags.iter() {
+        let (tag_ranked, tag_ranks) = calculate_tag_ranking(characters, tag);
+        println!(
+            "\n  - {:<42}{:>8}",
+            tag,
+            format!("#{}/{}", tag_ranks[&chara.id], tag_ranked.len())
+        );
+        println!("    {:-<50}", "");
+        for c in get_slice_in_ranked_chara(chara, &tag_ranked).iter() {
+            print_rank_entry(c, tag_ranks[&c.id], 4);
+        }
+    }
 
     // Stats
-    println!("\n==> {}", "STATISTICS");
+    println!("\n==> STATISTICS");
     let total = chara.hist.battles();
     println!(
         "    Wins:   {} ({}%)",
         chara.hist.wins,
-        if total == 0 {
-            0
-        } else {
-            100 * chara.hist.wins / total
-        }
+        (100 * chara.hist.wins).checked_div(total).unwrap_or(0)
     );
     println!("    Draws:  {}", chara.hist.draw);
     println!("    Losses: {}", chara.hist.loss);
 
     // Recent battles
     if !chara.hist.recent.is_empty() {
-        println!("\n==> {}", "RECENT BATTLES");
+        println!("\n==> RECENT BATTLES");
     }
     for m in chara.hist.recent.iter() {
         let msg = match m.res {
@@ -150,10 +184,17 @@ pub fn stat(
             MatchResult::BothLose => "Drew (lost)",
             MatchResult::AWin => "Won",
             MatchResult::BWin => "Lost",
+            MatchResult::AWeakWin => "Won (slightly)",
+            MatchResult::BWeakWin => "Lost (slightly)",
         };
         println!(
             "    {} against {} ({:.0})",
-            msg, m.oppo, characters[name_to_id[&m.oppo]].rank.rati
+            msg,
+            m.oppo,
+            name_to_id
+                .get(&m.oppo)
+                .and_then(|id| characters.iter().find(|c| c.id == *id))
+                .map_or(0.0, |c| c.rank.rati)
         );
     }
 
@@ -169,56 +210,297 @@ pub fn list_ranking(ranked_chara: &[Character], ranks: &HashMap<usize, usize>) {
     }
 }
 
-pub fn lobby_help() {
-    println!("-- 'start':   start a new session.");
-    println!("-- 'list':    show the ranking list.");
-    println!("-- 'stat':    see stats of a character.");
-    println!("-------------------------------------");
-    println!("-- 'help':    display this message.");
-    println!("-- 'exit':    See you next time.");
+// How long ago a unix time was, in the largest fitting unit
+fn age(time: u64, now: u64) -> String {
+    let secs = now.saturating_sub(time);
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
 }
 
-pub fn lobby_stat_help() {
-    println!("usage: stat <character name/ID>");
+// Print the sessions of the match log, with the rollback reaching back to each
+pub fn list_sessions(log: &[LogEntry], snapshots: &[Snapshot], now: u64) {
+    let rollbacks: HashMap<usize, usize> = snapshots
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, s)| (s.session, i + 1))
+        .collect();
+    println!("{:-<1$}", "", 46);
+    println!("Session  Matches  Age       Undo with");
+    println!("{:-<1$}", "", 46);
+    for session in log.chunk_by(|x, y| x.session == y.session) {
+        let id = session[0].session;
+        println!(
+            "{:<8} {:<8} {:<9} {}",
+            format!("#{}", id),
+            session.len(),
+            age(session[0].time, now),
+            rollbacks
+                .get(&id)
+                .map_or(String::new(), |n| format!("rollback {}", n))
+        );
+    }
 }
 
-pub fn start_fight(battle_id: usize, left: &str, right: &str) {
-    println!("-----------------------------");
-    println!("Battle #{}: {} vs {}", battle_id + 1, left, right);
-    print!("Pick [ 'h' for help ] >> ");
+// Print the leaderboards of several tags next to each other
+pub fn list_tag_rankings(boards: &[(String, Vec<Character>, HashMap<usize, usize>)], top: usize) {
+    const WIDTH: usize = 32;
+    let rows = boards.iter().map(|b| b.1.len().min(top)).max().unwrap_or(0);
+
+    let line = format!("{:-<1$}", "", WIDTH * boards.len());
+    println!("{}", line);
+    for (tag, ranked, _) in boards.iter() {
+        print!("{:<1$}", format!("[{}] ({})", tag, ranked.len()), WIDTH);
+    }
+    println!();
+    println!("{}", line);
+    for row in 0..rows {
+        for (_, ranked, ranks) in boards.iter() {
+            let cell = match ranked.get(row) {
+                Some(c) if row < top => format!(
+                    "{:<4} {:<14}{:>7.1}±{:.0}",
+                    format!("{}.", ranks[&c.id]),
+                    c.label(),
+                    c.rank.rati,
+                    c.rank.devi
+                ),
+                _ => String::new(),
+            };
+            print!("{:<1$}", cell, WIDTH);
+        }
+        println!();
+    }
 }
 
-pub fn fight_result(result: &str) {
-    println!("Chose - {}!", result);
+// The usage goes to stdout for --help and to stderr with an error
+pub fn usage(out: &mut dyn Write) {
+    let _ = writeln!(
+        out,
+        "usage: glicko2 [<command>] [--pool <name/path>] [--config <file>]"
+    );
+    let _ = writeln!(out, "               [--<option> <value>]...");
+    let _ = writeln!(out, "commands:");
+    let _ = writeln!(out, "    lobby [--matchmaker <name>] [--seed <u64>]");
+    let _ = writeln!(
+        out,
+        "                             interactive lobby (default), sessions pick pairs"
+    );
+    let _ = writeln!(
+        out,
+        "                             with {}",
+        MATCHMAKERS.join(", ")
+    );
+    let _ = writeln!(
+        out,
+        "                             a seed makes the pairings reproducible"
+    );
+    let _ = writeln!(out, "    list [--top <n>] [--tag <tag>]...");
+    let _ = writeln!(
+        out,
+        "                             show the ranking list, or the leaderboards"
+    );
+    let _ = writeln!(
+        out,
+        "                             of the given tags side by side"
+    );
+    let _ = writeln!(out, "    stat <name/ID>           see stats of a character");
+    let _ = writeln!(
+        out,
+        "    record <a> <b> <result>  record one match, result is one of"
+    );
+    let _ = writeln!(
+        out,
+        "                             a-win, b-win, draw, both-lose, a-weak-win,"
+    );
+    let _ = writeln!(out, "                             b-weak-win");
+    let _ = writeln!(out, "    play <file> [--matchmaker <name>] [--seed <u64>]");
+    let _ = writeln!(
+        out,
+        "                             run one session with the choices (11, 1, 0, 2,"
+    );
+    let _ = writeln!(
+        out,
+        "                             22, d, u, r, s) read line by line from a file,"
+    );
+    let _ = writeln!(out, "                             '-' for stdin");
+    let _ = writeln!(
+        out,
+        "    replay                   recompute all ratings from the match log, every"
+    );
+    let _ = writeln!(
+        out,
+        "                             logged character must still be in the roster"
+    );
+    let _ = writeln!(
+        out,
+        "    rename <name/ID> <new>   rename a character, the old name stays an alias"
+    );
+    let _ = writeln!(
+        out,
+        "    alias <name/ID> <alias>  let a character also be found by another name"
+    );
+    let _ = writeln!(
+        out,
+        "    exclude <name/ID>...     keep characters out of battles"
+    );
+    let _ = writeln!(
+        out,
+        "    include <name/ID>...     let excluded characters battle again"
+    );
+    let _ = writeln!(out, "    known <name/ID> [<name/ID>]");
+    let _ = writeln!(
+        out,
+        "                             take back the unknown mark of a character, or"
+    );
+    let _ = writeln!(
+        out,
+        "                             of a pair (from the s1/s2 and sp choices)"
+    );
+    let _ = writeln!(out, "    export [--top <n>] [--format <fmt>]");
+    let _ = writeln!(
+        out,
+        "                             print the ranking as csv (default) or json"
+    );
+    let _ = writeln!(out, "pools:");
+    let _ = writeln!(
+        out,
+        "    --pool selects a pool directory holding the roster (init.txt, or"
+    );
+    let _ = writeln!(
+        out,
+        "    roster.json with tags and other metadata), data.json and an"
+    );
+    let _ = writeln!(
+        out,
+        "    optional config.json. A value containing '/' is a path, any"
+    );
+    let _ = writeln!(
+        out,
+        "    other value names $XDG_DATA_HOME/glicko2/<name>. Defaults to"
+    );
+    let _ = writeln!(out, "    ${} or 'default'.", POOL_ENV);
+    let _ = writeln!(out, "options (override the config file):");
+    let _ = writeln!(
+        out,
+        "    --tau <f64>              volatility constraint (0.3 ~ 1.2)"
+    );
+    let _ = writeln!(out, "    --epsilon <f64>          convergence tolerance");
+    let _ = writeln!(out, "    --init-rating <f64>      rating of new characters");
+    let _ = writeln!(
+        out,
+        "    --init-deviation <f64>   rating deviation of new characters"
+    );
+    let _ = writeln!(
+        out,
+        "    --init-volatility <f64>  volatility of new characters"
+    );
+    let _ = writeln!(
+        out,
+        "    --inflate-idle <bool>    grow the deviation of idle characters"
+    );
+    let _ = writeln!(
+        out,
+        "    --period-length <secs>   length of a rating period (0: per session)"
+    );
+    let _ = writeln!(
+        out,
+        "    --weak-win <f64>         score of a slight win (0.5 ~ 1, default 0.75)"
+    );
 }
 
-pub fn fight_dislike_both() {
-    println!("Disliked both!");
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
 }
 
-pub fn fight_undo() {
-    println!("Undoing...");
+pub fn export_csv(ranked_chara: &[Character], ranks: &HashMap<usize, usize>) {
+    println!("rank,id,name,display,tags,active,rating,deviation,volatility,wins,draws,losses");
+    for c in ranked_chara.iter() {
+        println!(
+            "{},{},{},{},{},{},{:.2},{:.2},{:.6},{},{},{}",
+            ranks[&c.id],
+            c.id,
+            csv_field(&c.name),
+            csv_field(c.label()),
+            csv_field(&c.meta.tags.join(";")),
+            c.meta.active,
+            c.rank.rati,
+            c.rank.devi,
+            c.rank.vola,
+            c.hist.wins,
+            c.hist.draw,
+            c.hist.loss
+        );
+    }
 }
 
-pub fn fight_undo_err() {
-    println!("This is the first battle!");
+pub fn export_json(ranked_chara: &[Character], ranks: &HashMap<usize, usize>) {
+    let list: Vec<_> = ranked_chara
+        .iter()
+        .map(|c| {
+            json!({
+                "rank": ranks[&c.id],
+                "id": c.id,
+                "name": c.name,
+                "display": c.label(),
+                "tags": c.meta.tags,
+                "image": c.meta.image,
+                "active": c.meta.active,
+                "rating": c.rank.rati,
+                "deviation": c.rank.devi,
+                "volatility": c.rank.vola,
+                "wins": c.hist.wins,
+                "draws": c.hist.draw,
+                "losses": c.hist.loss,
+            })
+        })
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&list).unwrap());
 }
 
-pub fn fight_help() {
-    println!("1/2 to choose left/right");
-    println!("0 for draws");
-    println!("d if you DISLIKE BOTH of them");
-    println!("u to UNDO");
-    println!("<Enter> to end this session");
+pub fn lobby_help() {
+    println!("-- 'start':   start a new session.");
+    println!("--            'start <matchmaker>' to choose how pairs are picked.");
+    println!("-- 'list':    show the ranking list.");
+    println!("--            'list <tag>...' for leaderboards of tags.");
+    println!("-- 'stat':    see stats of a character.");
+    println!("-- 'exclude': keep a character out of battles.");
+    println!("-- 'include': let an excluded character battle again.");
+    println!("-- 'known':   take back the unknown mark of a character,");
+    println!("--            'known <a> <b>' the unknown mark of a pair.");
+    println!("-- 'sessions': list the past sessions.");
+    println!("-- 'rollback': undo the last session, 'rollback <n>' the last n.");
+    println!("-------------------------------------");
+    println!("-- 'help':    display this message.");
+    println!("-- 'exit':    See you next time.");
+}
+
+pub fn lobby_start_help() {
+    println!("usage: start [{}]", MATCHMAKERS.join("/"));
+}
+
+pub fn lobby_exclude_help() {
+    println!("usage: exclude/include <character name/ID>");
 }
 
-pub fn fight_end() {
-    println!("Finish rating session.");
+pub fn lobby_known_help() {
+    println!("usage: known <character name/ID> [<character name/ID>]");
 }
 
-pub fn start_session(num_characters: usize) {
+pub fn lobby_rollback_help(available: usize) {
     println!(
-        "=== Starting a new session with {} characters ===",
-        num_characters
+        "usage: rollback [n], {} sessions can be rolled back",
+        available
     );
 }
+
+pub fn lobby_stat_help() {
+    println!("usage: stat <character name/ID>");
+}
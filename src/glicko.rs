@@ -1,12 +1,9 @@
-use crate::structs::{Battle, Character, Match, MatchResult};
+use crate::{
+    config::{GlickoConfig, GLICKO2_SCALE},
+    structs::{index_by_id, Battle, Character, History, LogEntry, Match, MatchResult, Rank},
+};
 use std::{collections::HashMap, f64::consts::PI};
 
-// The system constant which constrains the change in volatility over time, needs to be set prior to application of the system
-// Reasonable choices are between 0.3 and 1.2
-const TAU: f64 = 0.5;
-// Convergence tolerance
-const EPSILON: f64 = 1e-6;
-
 // The maximum number of old ratings/ranks stored
 const MAX_HIST: usize = 5;
 
@@ -24,10 +21,12 @@ fn part_v(mu: f64, mu_j: f64, phi_j: f64) -> f64 {
 }
 
 fn part_d(mu: f64, mu_j: f64, phi_j: f64, s: f64) -> f64 {
-    g(phi_j) * (s as f64 - e(mu, mu_j, phi_j))
+    g(phi_j) * (s - e(mu, mu_j, phi_j))
 }
 
-fn new_volatility(v: f64, delta: f64, sigma: f64, phi: f64, tau: f64, epsilon: f64) -> f64 {
+fn new_volatility(v: f64, delta: f64, sigma: f64, phi: f64, config: &GlickoConfig) -> f64 {
+    let tau = config.tau;
+    let epsilon = config.epsilon;
     let _a = sigma.powi(2).ln();
     let delta2 = delta * delta;
     let tau2 = tau * tau;
@@ -67,16 +66,64 @@ fn new_volatility(v: f64, delta: f64, sigma: f64, phi: f64, tau: f64, epsilon: f
     (a / 2.0).exp()
 }
 
-fn new_deviation(phi: f64, new_sigma: f64, v: f64) -> f64 {
-    let phi_star = (phi * phi + new_sigma * new_sigma).sqrt();
+fn new_deviation(phi_star: f64, v: f64) -> f64 {
     1.0 / (1.0 / phi_star.powi(2) + 1.0 / v.powi(2)).sqrt()
 }
 
+// The deviation after the elapsed rating periods, before any match counts.
+// It grows up to a new player's, but never shrinks when it is already above
+// (e.g. after lowering init_devi)
+fn pre_period_deviation(phi: f64, sigma: f64, periods: f64, config: &GlickoConfig) -> f64 {
+    let phi_max = config.init_devi / GLICKO2_SCALE;
+    (phi * phi + periods * sigma * sigma)
+        .sqrt()
+        .min(phi_max.max(phi))
+}
+
+// The number of rating periods elapsed since the rank was last updated
+fn elapsed_periods(rank: &Rank, now: u64, config: &GlickoConfig) -> f64 {
+    if config.period_len == 0 || rank.time == 0 {
+        // Every session is exactly one rating period
+        return 1.0;
+    }
+    now.saturating_sub(rank.time) as f64 / config.period_len as f64
+}
+
 fn new_rating(mu: f64, new_phi: f64, v: f64, delta: f64) -> f64 {
     mu + new_phi * delta / v
 }
 
-pub fn calculate_results(characters: &mut Vec<Character>, records: &[Match]) {
+// The variance (Glicko-2 scale) expected after one more match against the
+// opponent, whatever its outcome
+fn expected_variance(mu: f64, phi: f64, mu_j: f64, phi_j: f64) -> f64 {
+    1.0 / (1.0 / (phi * phi) + part_v(mu, mu_j, phi_j))
+}
+
+// The expected decrease of the rating variances of both characters if they
+// play each other. It is large for uncertain characters with close ratings
+pub fn expected_information(a: &Rank, b: &Rank) -> f64 {
+    let (mut a, mut b) = (a.clone(), b.clone());
+    a.glicko_1_to_2_scale();
+    b.glicko_1_to_2_scale();
+    let var_a = expected_variance(a.rati, a.devi, b.rati, b.devi);
+    let var_b = expected_variance(b.rati, b.devi, a.rati, a.devi);
+    (a.devi * a.devi - var_a) + (b.devi * b.devi - var_b)
+}
+
+// The deviation (Glicko-1 scale) expected after one more match against the opponent
+pub fn expected_deviation(rank: &Rank, oppo: &Rank) -> f64 {
+    let (mut a, mut b) = (rank.clone(), oppo.clone());
+    a.glicko_1_to_2_scale();
+    b.glicko_1_to_2_scale();
+    expected_variance(a.rati, a.devi, b.rati, b.devi).sqrt() * GLICKO2_SCALE
+}
+
+pub fn calculate_results(
+    characters: &mut [Character],
+    records: &[Match],
+    config: &GlickoConfig,
+    now: u64,
+) {
     if records.is_empty() {
         return;
     }
@@ -101,17 +148,14 @@ pub fn calculate_results(characters: &mut Vec<Character>, records: &[Match]) {
         delta.insert(m.b, 0.0);
     }
 
+    let index = index_by_id(characters);
     for m in records.iter() {
-        let mu1 = characters[m.a].rank.rati;
-        let mu2 = characters[m.b].rank.rati;
-        let phi1 = characters[m.a].rank.devi;
-        let phi2 = characters[m.b].rank.devi;
-        let (s1, s2) = match m.res {
-            MatchResult::AWin => (1.0, 0.0),
-            MatchResult::BWin => (0.0, 1.0),
-            MatchResult::Draw => (0.5, 0.5),
-            MatchResult::BothLose => (0.0, 0.0),
-        };
+        let (a, b) = (&characters[index[&m.a]], &characters[index[&m.b]]);
+        let mu1 = a.rank.rati;
+        let mu2 = b.rank.rati;
+        let phi1 = a.rank.devi;
+        let phi2 = b.rank.devi;
+        let (s1, s2) = m.res.scores(config.weak_win);
 
         // Add up the quantities calculated by matches with others players
         if let Some(v1) = v.get_mut(&m.a) {
@@ -138,22 +182,24 @@ pub fn calculate_results(characters: &mut Vec<Character>, records: &[Match]) {
     }
 
     for c in characters.iter_mut() {
+        let periods = elapsed_periods(&c.rank, now, config);
         if !v.contains_key(&c.id) {
+            // If the player does not compete during the rating period,
+            // only the rating deviation increases (never above a new player's)
+            if config.inflate_idle {
+                c.rank.devi = pre_period_deviation(c.rank.devi, c.rank.vola, periods, config);
+                c.rank.time = now;
+            }
             // Convert the ratings and RD’s onto the Glicko-1 scale
             c.rank.glicko_2_to_1_scale();
             continue;
         }
         // Determine the new value of the volatility, deviation, and rating
-        c.rank.vola = new_volatility(
-            v[&c.id],
-            delta[&c.id],
-            c.rank.vola,
-            c.rank.devi,
-            TAU,
-            EPSILON,
-        );
-        c.rank.devi = new_deviation(c.rank.devi, c.rank.vola, v[&c.id]);
+        c.rank.vola = new_volatility(v[&c.id], delta[&c.id], c.rank.vola, c.rank.devi, config);
+        let phi_star = pre_period_deviation(c.rank.devi, c.rank.vola, periods, config);
+        c.rank.devi = new_deviation(phi_star, v[&c.id]);
         c.rank.rati = new_rating(c.rank.rati, c.rank.devi, v[&c.id], delta[&c.id]);
+        c.rank.time = now;
 
         // Convert the ratings and RD’s onto the Glicko-1 scale
         c.rank.glicko_2_to_1_scale();
@@ -174,7 +220,7 @@ pub fn calculate_ranking(characters: &[Character]) -> (Vec<Character>, HashMap<u
 
     let mut ranks: HashMap<usize, usize> = HashMap::with_capacity(characters.len());
     let mut rank = 1;
-    let mut max_rating = list[0].rank.rati;
+    let mut max_rating = list.first().map_or(0.0, |c| c.rank.rati);
     for c in list.iter() {
         if c.rank.rati < max_rating {
             rank += 1;
@@ -186,53 +232,27 @@ pub fn calculate_ranking(characters: &[Character]) -> (Vec<Character>, HashMap<u
     (list, ranks)
 }
 
+// Rank only the characters with the given tag among themselves
+pub fn calculate_tag_ranking(
+    characters: &[Character],
+    tag: &str,
+) -> (Vec<Character>, HashMap<usize, usize>) {
+    let group: Vec<Character> = characters
+        .iter()
+        .filter(|c| c.has_tag(tag))
+        .cloned()
+        .collect();
+    calculate_ranking(&group)
+}
+
 pub fn update_history(
-    characters: &mut Vec<Character>,
+    characters: &mut [Character],
     records: &[Match],
     ranks: &HashMap<usize, usize>,
 ) {
+    let index = index_by_id(characters);
     for m in records.iter() {
-        match m.res {
-            MatchResult::AWin => {
-                characters[m.a].hist.wins += 1;
-                characters[m.b].hist.loss += 1;
-            }
-            MatchResult::BWin => {
-                characters[m.a].hist.loss += 1;
-                characters[m.b].hist.wins += 1;
-            }
-            MatchResult::Draw => {
-                characters[m.a].hist.draw += 1;
-                characters[m.b].hist.draw += 1;
-            }
-            MatchResult::BothLose => {
-                characters[m.a].hist.loss += 1;
-                characters[m.b].hist.loss += 1;
-            }
-        };
-
-        let name_a = characters[m.a].name.clone();
-        let name_b = characters[m.b].name.clone();
-        let (res_a, res_b) = match m.res {
-            MatchResult::AWin => (MatchResult::AWin, MatchResult::BWin),
-            MatchResult::BWin => (MatchResult::BWin, MatchResult::AWin),
-            MatchResult::Draw => (MatchResult::Draw, MatchResult::Draw),
-            MatchResult::BothLose => (MatchResult::BothLose, MatchResult::BothLose),
-        };
-        characters[m.a]
-            .hist
-            .recent
-            .push_back(Battle::new(name_b, res_a));
-        characters[m.b]
-            .hist
-            .recent
-            .push_back(Battle::new(name_a, res_b));
-        while characters[m.a].hist.recent.len() > MAX_HIST {
-            characters[m.a].hist.recent.pop_front();
-        }
-        while characters[m.b].hist.recent.len() > MAX_HIST {
-            characters[m.b].hist.recent.pop_front();
-        }
+        record_match(characters, index[&m.a], index[&m.b], &m.res);
     }
     for c in characters.iter_mut() {
         c.hist.old_rate.push_back(c.rank.rati);
@@ -243,3 +263,103 @@ pub fn update_history(
         }
     }
 }
+
+// Count a match in the stats and recent battles of both sides,
+// a and b are positions in characters
+pub(crate) fn record_match(characters: &mut [Character], a: usize, b: usize, res: &MatchResult) {
+    match res {
+        MatchResult::AWin | MatchResult::AWeakWin => {
+            characters[a].hist.wins += 1;
+            characters[b].hist.loss += 1;
+        }
+        MatchResult::BWin | MatchResult::BWeakWin => {
+            characters[a].hist.loss += 1;
+            characters[b].hist.wins += 1;
+        }
+        MatchResult::Draw => {
+            characters[a].hist.draw += 1;
+            characters[b].hist.draw += 1;
+        }
+        MatchResult::BothLose => {
+            characters[a].hist.loss += 1;
+            characters[b].hist.loss += 1;
+        }
+    };
+
+    let name_a = characters[a].name.clone();
+    let name_b = characters[b].name.clone();
+    let (res_a, res_b) = (res.clone(), res.swapped());
+    characters[a]
+        .hist
+        .recent
+        .push_back(Battle::new(name_b, res_a));
+    characters[b]
+        .hist
+        .recent
+        .push_back(Battle::new(name_a, res_b));
+    while characters[a].hist.recent.len() > MAX_HIST {
+        characters[a].hist.recent.pop_front();
+    }
+    while characters[b].hist.recent.len() > MAX_HIST {
+        characters[b].hist.recent.pop_front();
+    }
+}
+
+// Reset every character to the initial rank and rebuild ratings and history
+// by applying the logged sessions in order, as a live run would have done.
+// A session only counts the characters which had joined the pool by then
+pub fn replay(
+    characters: &mut [Character],
+    log: &[LogEntry],
+    config: &GlickoConfig,
+) -> Result<usize, String> {
+    let index = index_by_id(characters);
+    let mut first_battle: Vec<Option<u64>> = vec![None; characters.len()];
+    for entry in log.iter() {
+        let m = &entry.game;
+        let (Some(&a), Some(&b)) = (index.get(&m.a), index.get(&m.b)) else {
+            return Err(format!(
+                "session {} has a match between unknown characters #{} and #{}, \
+                 put them back into the roster to replay",
+                entry.session, m.a, m.b
+            ));
+        };
+        first_battle[a].get_or_insert(entry.time);
+        first_battle[b].get_or_insert(entry.time);
+    }
+    // A character has joined at the latest when it first battled, data
+    // without join times only has that
+    let since: Vec<u64> = characters
+        .iter()
+        .zip(first_battle)
+        .map(|(c, first)| match first {
+            Some(time) if c.since == 0 => time,
+            Some(time) => c.since.min(time),
+            None => c.since,
+        })
+        .collect();
+
+    for c in characters.iter_mut() {
+        c.rank = Rank::new(config);
+        c.hist = History::new();
+    }
+
+    let mut sessions = 0;
+    for session in log.chunk_by(|x, y| x.session == y.session) {
+        let records: Vec<Match> = session.iter().map(|entry| entry.game.clone()).collect();
+        let time = session[0].time;
+        let joined: Vec<usize> = (0..characters.len())
+            .filter(|&i| since[i] <= time)
+            .collect();
+        let mut present: Vec<Character> = joined.iter().map(|&i| characters[i].clone()).collect();
+        let (_, ranks) = calculate_ranking(&present);
+        update_history(&mut present, &records, &ranks);
+        calculate_results(&mut present, &records, config, time);
+        for (i, c) in joined.into_iter().zip(present) {
+            characters[i] = c;
+        }
+        sessions += 1;
+    }
+
+    Ok(sessions)
+}
@@ -0,0 +1,21 @@
+/*
+Implementation of Glicko2 Rating System
+Paper: http://www.glicko.net/glicko/glicko2.pdf
+
+The library only contains the rating types, the math of the system and
+the battle loop, which reads choices from any reader and writes its
+transcript to any writer, so it can be embedded without any terminal or
+file handling.
+*/
+
+pub mod battle;
+pub mod config;
+pub mod glicko;
+pub mod matchmaker;
+pub mod structs;
+
+pub use crate::{
+    config::GlickoConfig,
+    glicko::{calculate_ranking, calculate_results, calculate_tag_ranking, replay, update_history},
+    structs::{Battle, Character, History, LogEntry, Match, MatchResult, Meta, Rank, Snapshot},
+};
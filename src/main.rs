@@ -3,28 +3,207 @@ Implementation of Glicko2 Rating System
 Paper: http://www.glicko.net/glicko/glicko2.pdf
 */
 
-mod battle;
+mod cli;
 mod display;
-mod glicko;
-mod structs;
+mod storage;
 
 use crate::{
-    battle::battles,
+    cli::{Command, ExportFormat, EXIT_FAILURE},
     display::{list_ranking, stat},
-    glicko::{calculate_ranking, calculate_results, update_history},
-    structs::{initialize_characters, store_characters, Character},
+    storage::{
+        append_match_log, initialize_characters, is_stored, read_match_log, read_session_journal,
+        read_snapshots, remove_session_journal, store_characters, truncate_match_log,
+        write_session_journal, write_snapshots, Pool,
+    },
 };
+use glicko2::{
+    battle::{battles, Session},
+    config::GlickoConfig,
+    glicko::{calculate_ranking, calculate_results, calculate_tag_ranking, replay, update_history},
+    matchmaker::{matchmaker_by_name, session_rng, MATCHMAKERS},
+    structs::{add_alias, mark_known, rename_character, unix_now, Character, Match, Snapshot},
+};
+use rand::RngCore;
 use std::{
     collections::HashMap,
-    io::{self, Write},
+    env,
+    fs::File,
+    io::{self, BufRead, BufReader, Write},
+    process::exit,
 };
 
 fn main() {
-    let (mut characters, name_to_id) = initialize_characters();
-    let (mut ranked_chara, mut ranks) = calculate_ranking(&characters);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = cli::parse_args(&args);
+
+    // Only the lobby may ask questions, the other commands can be scripted
+    let interactive = matches!(opts.command, Command::Lobby);
+    let (mut characters, name_to_id) = initialize_characters(&opts.pool, &opts.config, interactive);
+    let (ranked_chara, ranks) = calculate_ranking(&characters);
+
+    match opts.command {
+        Command::Lobby => {
+            lobby(
+                &mut characters,
+                &name_to_id,
+                &opts.pool,
+                &opts.config,
+                &opts.matchmaker,
+                opts.seed,
+            );
+        }
+        Command::List { top, tags } => {
+            list_tags(&characters, &tags, top, &ranked_chara, &ranks);
+        }
+        Command::Stat { chara } => {
+            let i = find_or_exit(&chara, &characters, &name_to_id);
+            stat(
+                &characters[i],
+                &characters,
+                &name_to_id,
+                &ranked_chara,
+                &ranks,
+            );
+        }
+        Command::Record { a, b, res } => {
+            let a = characters[find_or_exit(&a, &characters, &name_to_id)].id;
+            let b = characters[find_or_exit(&b, &characters, &name_to_id)].id;
+            if a == b {
+                eprintln!("Error: a character cannot battle against itself");
+                exit(EXIT_FAILURE);
+            }
+            let records = vec![Match::new(a, b, res)];
+            if !finish_session(&mut characters, &records, &ranks, &opts.pool, &opts.config) {
+                exit(EXIT_FAILURE);
+            }
+        }
+        Command::Play { script } => {
+            let mut input: Box<dyn BufRead> = if script == "-" {
+                Box::new(io::stdin().lock())
+            } else {
+                match File::open(&script) {
+                    Ok(file) => Box::new(BufReader::new(file)),
+                    Err(error) => {
+                        eprintln!("Error: failed to read {}: {}", script, error);
+                        exit(EXIT_FAILURE);
+                    }
+                }
+            };
+            // The matchmaker was validated while parsing the arguments
+            let mut mm = matchmaker_by_name(&opts.matchmaker).unwrap();
+            let session = battles(
+                Session::new(&characters, &opts.matchmaker),
+                &name_to_id,
+                mm.as_mut(),
+                &mut session_rng(opts.seed),
+                input.as_mut(),
+                &mut io::stdout(),
+                &mut |_| {},
+            );
+            let saved = apply_session(&mut characters, &session, &ranks, &opts.pool, &opts.config);
+            if saved == Some(false) {
+                exit(EXIT_FAILURE);
+            }
+        }
+        Command::Replay => {
+            let log = match read_match_log(&opts.pool) {
+                Ok(log) => log,
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    exit(EXIT_FAILURE);
+                }
+            };
+            match replay(&mut characters, &log, &opts.config) {
+                Ok(sessions) => {
+                    save_or_exit(&opts.pool, &characters);
+                    // The snapshots were taken from the ratings before the replay
+                    if let Err(error) = write_snapshots(&opts.pool, &[]) {
+                        eprintln!("Error: failed to clear the snapshots: {}", error);
+                    }
+                    println!("Replayed {} sessions ({} matches).", sessions, log.len());
+                }
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    exit(EXIT_FAILURE);
+                }
+            }
+        }
+        Command::Rename { chara, name } => {
+            let id = characters[find_or_exit(&chara, &characters, &name_to_id)].id;
+            if let Err(error) = rename_character(&mut characters, id, &name) {
+                eprintln!("Error: {}", error);
+                exit(EXIT_FAILURE);
+            }
+            save_or_exit(&opts.pool, &characters);
+            println!("Renamed {} to {}.", chara, name);
+        }
+        Command::Alias { chara, alias } => {
+            let id = characters[find_or_exit(&chara, &characters, &name_to_id)].id;
+            if let Err(error) = add_alias(&mut characters, id, &alias) {
+                eprintln!("Error: {}", error);
+                exit(EXIT_FAILURE);
+            }
+            save_or_exit(&opts.pool, &characters);
+            println!("{} is also known as {}.", chara, alias);
+        }
+        Command::Exclude { charas, excluded } => {
+            for chara in charas.iter() {
+                let i = find_or_exit(chara, &characters, &name_to_id);
+                characters[i].excluded = excluded;
+            }
+            save_or_exit(&opts.pool, &characters);
+        }
+        Command::Known { chara, other } => {
+            let id = characters[find_or_exit(&chara, &characters, &name_to_id)].id;
+            let other = other.map(|o| characters[find_or_exit(&o, &characters, &name_to_id)].id);
+            if !mark_known(&mut characters, id, other) {
+                eprintln!(
+                    "Error: {} is not marked unknown",
+                    unknown_mark(&characters, id, other)
+                );
+                exit(EXIT_FAILURE);
+            }
+            save_or_exit(&opts.pool, &characters);
+            println!(
+                "{} is no longer marked unknown.",
+                unknown_mark(&characters, id, other)
+            );
+        }
+        Command::Export { top, format } => match format {
+            ExportFormat::Csv => display::export_csv(take_top(&ranked_chara, top), &ranks),
+            ExportFormat::Json => display::export_json(take_top(&ranked_chara, top), &ranks),
+        },
+    }
+}
+
+fn lobby(
+    characters: &mut [Character],
+    name_to_id: &HashMap<String, usize>,
+    pool: &Pool,
+    config: &GlickoConfig,
+    matchmaker: &str,
+    seed: Option<u64>,
+) {
+    let (mut ranked_chara, mut ranks) = calculate_ranking(characters);
+    // One random source for all sessions, a seed makes the whole lobby reproducible
+    let mut rng = session_rng(seed);
 
     let mut choice: String = String::new();
     println!("=========~ Glicko2: Lobby ~=========");
+
+    // Offer to finish a session which was interrupted before it was applied
+    if let Some(session) = read_session_journal(pool) {
+        match resume_or_discard(session, characters) {
+            Some(session) => {
+                let session = journaled_battles(session, name_to_id, &mut rng, pool);
+                if apply_session(characters, &session, &ranks, pool, config).is_some() {
+                    (ranked_chara, ranks) = calculate_ranking(characters);
+                }
+            }
+            None => remove_session_journal(pool),
+        }
+    }
+
     display::lobby_help();
     loop {
         print!("Lobby >> ");
@@ -35,14 +214,95 @@ fn main() {
 
         choice = choice.trim().to_string();
         if choice.starts_with("star") {
-            let records = battles(&characters, &name_to_id);
-            update_history(&mut characters, &records, &ranks);
-            calculate_results(&mut characters, &records);
-            (ranked_chara, ranks) = calculate_ranking(&characters);
+            // "start <matchmaker>" overrides the matchmaker for one session
+            let name = choice.split_whitespace().nth(1).unwrap_or(matchmaker);
+            if matchmaker_by_name(name).is_none() {
+                display::lobby_start_help();
+                continue;
+            }
+            let session = Session::new(characters, name);
+            let session = journaled_battles(session, name_to_id, &mut rng, pool);
+            // Save after every session so a crash loses at most the current one
+            if apply_session(characters, &session, &ranks, pool, config).is_some() {
+                (ranked_chara, ranks) = calculate_ranking(characters);
+            }
         } else if choice.starts_with("l") {
-            list_ranking(&ranked_chara, &ranks);
+            // "list <tag> <tag>..." shows the leaderboards of tags
+            let tags: Vec<String> = choice
+                .split_whitespace()
+                .skip(1)
+                .map(String::from)
+                .collect();
+            list_tags(characters, &tags, None, &ranked_chara, &ranks);
         } else if choice.starts_with("stat") {
-            handle_stat(&mut choice, &characters, &name_to_id, &ranked_chara, &ranks);
+            handle_stat(&mut choice, characters, name_to_id, &ranked_chara, &ranks);
+        } else if choice.starts_with("exclude") || choice.starts_with("include") {
+            let excluded = choice.starts_with("exclude");
+            let ch = choice.split_off(7).trim().to_string();
+            match find_character(&ch, characters, name_to_id) {
+                Some(i) => {
+                    characters[i].excluded = excluded;
+                    (ranked_chara, ranks) = calculate_ranking(characters);
+                    save(pool, characters);
+                }
+                None => display::lobby_exclude_help(),
+            }
+        } else if choice.starts_with("known") {
+            let words: Vec<&str> = choice.split_whitespace().skip(1).collect();
+            let find = |key: &[&str]| {
+                find_character(&key.join(" "), characters, name_to_id).map(|i| characters[i].id)
+            };
+            // One character, or a pair split at the first place naming two characters
+            let mark = match find(&words) {
+                Some(id) => Some((id, None)),
+                None => (1..words.len())
+                    .find_map(|k| Some((find(&words[..k])?, Some(find(&words[k..])?)))),
+            };
+            let Some((id, other)) = mark else {
+                display::lobby_known_help();
+                continue;
+            };
+            if mark_known(characters, id, other) {
+                println!(
+                    "{} is no longer marked unknown.",
+                    unknown_mark(characters, id, other)
+                );
+                (ranked_chara, ranks) = calculate_ranking(characters);
+                save(pool, characters);
+            } else {
+                println!(
+                    "{} is not marked unknown.",
+                    unknown_mark(characters, id, other)
+                );
+            }
+        } else if choice.starts_with("sess") {
+            match read_match_log(pool).and_then(|log| Ok((log, read_snapshots(pool)?))) {
+                Ok((log, snapshots)) => display::list_sessions(&log, &snapshots, unix_now()),
+                Err(error) => eprintln!("Error: {}", error),
+            }
+        } else if choice.starts_with("rollback") {
+            let snapshots = match read_snapshots(pool) {
+                Ok(snapshots) => snapshots,
+                Err(error) => {
+                    eprintln!("Error: {}", error);
+                    continue;
+                }
+            };
+            let n = match choice.split_whitespace().nth(1).map(str::parse::<usize>) {
+                None => 1,
+                Some(Ok(n)) if (1..=snapshots.len()).contains(&n) => n,
+                Some(_) => {
+                    display::lobby_rollback_help(snapshots.len());
+                    continue;
+                }
+            };
+            if snapshots.is_empty() {
+                display::lobby_rollback_help(0);
+                continue;
+            }
+            if rollback(characters, &snapshots, n, pool) {
+                (ranked_chara, ranks) = calculate_ranking(characters);
+            }
         } else if choice.starts_with("h") {
             display::lobby_help();
         } else {
@@ -50,7 +310,276 @@ fn main() {
         }
     }
 
-    store_characters(&characters);
+    // Sessions are saved as they finish, anything else (what the loader
+    // changed, a failed save) is saved on exit
+    if !is_stored(pool, characters) {
+        save(pool, characters);
+    }
+}
+
+// Run a session at the terminal, keeping its progress in the journal of the pool.
+// The journal is gone once the session ends, before the session is applied,
+// so a crash while applying loses the session instead of applying it twice
+fn journaled_battles(
+    session: Session,
+    name_to_id: &HashMap<String, usize>,
+    rng: &mut dyn RngCore,
+    pool: &Pool,
+) -> Session {
+    let mut mm = matchmaker_by_name(&session.matchmaker)
+        .unwrap_or_else(|| matchmaker_by_name(MATCHMAKERS[0]).unwrap());
+    let session = battles(
+        session,
+        name_to_id,
+        mm.as_mut(),
+        rng,
+        &mut io::stdin().lock(),
+        &mut io::stdout(),
+        &mut |session| {
+            if let Err(error) = write_session_journal(pool, session) {
+                eprintln!(
+                    "Error: failed to save {}: {}",
+                    pool.session_path().display(),
+                    error
+                );
+            }
+        },
+    );
+    remove_session_journal(pool);
+    session
+}
+
+// Ask whether to resume an interrupted session, returns None to discard it
+fn resume_or_discard(session: Session, characters: &[Character]) -> Option<Session> {
+    let Some(session) = session.remap(characters) else {
+        println!("A character of the unfinished session no longer exists, discard it.");
+        return None;
+    };
+    println!(
+        "Found an unfinished session of {} battles.",
+        session.records.len()
+    );
+    print!("Do you want to RESUME it ? (Y/n) ");
+    let mut choice: String = String::new();
+    let _ = io::stdout().flush();
+    let _ = io::stdin().read_line(&mut choice);
+    if choice.to_uppercase().starts_with("N") {
+        println!("Discarded the session.");
+        return None;
+    }
+    Some(session)
+}
+
+// Undo the last n sessions after asking, returns whether the characters changed.
+// Nothing changes, in memory or in the files, unless the restored data was saved
+fn rollback(characters: &mut [Character], snapshots: &[Snapshot], n: usize, pool: &Pool) -> bool {
+    let keep = snapshots.len() - n;
+    let target = &snapshots[keep];
+    let matches: usize = snapshots[keep..].iter().map(|s| s.matches).sum();
+    print!(
+        "Do you want to ROLL BACK {} sessions ({} matches) ? (y/N) ",
+        n, matches
+    );
+    let mut choice: String = String::new();
+    let _ = io::stdout().flush();
+    let _ = io::stdin().read_line(&mut choice);
+    if !choice.to_uppercase().starts_with("Y") {
+        println!("Keep them.");
+        return false;
+    }
+
+    let mut restored = characters.to_vec();
+    target.restore(&mut restored);
+    if !save(pool, &restored) {
+        println!("Nothing was rolled back.");
+        return false;
+    }
+    characters.clone_from_slice(&restored);
+    // The data is restored, now forget the sessions
+    let forget = truncate_match_log(pool, target.session)
+        .and_then(|()| write_snapshots(pool, &snapshots[..keep]));
+    if let Err(error) = forget {
+        eprintln!("Error: failed to drop the rolled back sessions: {}", error);
+    }
+    println!("Rolled back to before session #{}.", target.session);
+    true
+}
+
+// Apply the results and the unknown marks of a session, returns whether
+// they were saved, None if the session changed nothing
+fn apply_session(
+    characters: &mut [Character],
+    session: &Session,
+    ranks: &HashMap<usize, usize>,
+    pool: &Pool,
+    config: &GlickoConfig,
+) -> Option<bool> {
+    if session.records.is_empty() {
+        return session
+            .mark_unknown(characters)
+            .then(|| save(pool, characters));
+    }
+    // The snapshot is taken before the marks, so a rollback takes them back
+    let now = log_session(characters, &session.records, pool);
+    session.mark_unknown(characters);
+    Some(rate_session(
+        characters,
+        &session.records,
+        ranks,
+        pool,
+        config,
+        now,
+    ))
+}
+
+// Log the matches of a session and rate them as one rating period,
+// returns whether the result was saved
+fn finish_session(
+    characters: &mut [Character],
+    records: &[Match],
+    ranks: &HashMap<usize, usize>,
+    pool: &Pool,
+    config: &GlickoConfig,
+) -> bool {
+    if records.is_empty() {
+        return true;
+    }
+    let now = log_session(characters, records, pool);
+    rate_session(characters, records, ranks, pool, config, now)
+}
+
+// Append the matches of a session to the match log and keep a snapshot of
+// the characters before it, returns the time of the session. The ratings are
+// applied anyway, a session missing from the log only cannot be replayed or
+// rolled back
+fn log_session(characters: &[Character], records: &[Match], pool: &Pool) -> u64 {
+    let now = unix_now();
+    // Read the snapshots first, nothing may fail between logging and rating
+    let snapshots = match read_snapshots(pool) {
+        Ok(snapshots) => Some(snapshots),
+        Err(error) => {
+            eprintln!("Error: {}, the session cannot be rolled back", error);
+            None
+        }
+    };
+    let mut snapshot = Snapshot::take(0, now, records.len(), characters);
+    match append_match_log(pool, records, now) {
+        Ok(session) => {
+            if let Some(mut snapshots) = snapshots {
+                snapshot.session = session;
+                snapshots.push(snapshot);
+                if let Err(error) = write_snapshots(pool, &snapshots) {
+                    eprintln!(
+                        "Error: failed to save {}: {}",
+                        pool.snapshot_path().display(),
+                        error
+                    );
+                }
+            }
+        }
+        Err(error) => eprintln!(
+            "Error: failed to log the session in {}: {}",
+            pool.log_path().display(),
+            error
+        ),
+    }
+    now
+}
+
+fn rate_session(
+    characters: &mut [Character],
+    records: &[Match],
+    ranks: &HashMap<usize, usize>,
+    pool: &Pool,
+    config: &GlickoConfig,
+    now: u64,
+) -> bool {
+    update_history(characters, records, ranks);
+    calculate_results(characters, records, config, now);
+    save(pool, characters)
+}
+
+fn save(pool: &Pool, characters: &[Character]) -> bool {
+    match store_characters(pool, characters) {
+        Ok(()) => true,
+        Err(error) => {
+            eprintln!(
+                "Error: failed to save {}: {}",
+                pool.data_path().display(),
+                error
+            );
+            false
+        }
+    }
+}
+
+fn save_or_exit(pool: &Pool, characters: &[Character]) {
+    if !save(pool, characters) {
+        exit(EXIT_FAILURE);
+    }
+}
+
+fn list_tags(
+    characters: &[Character],
+    tags: &[String],
+    top: Option<usize>,
+    ranked_chara: &[Character],
+    ranks: &HashMap<usize, usize>,
+) {
+    match tags {
+        [] => list_ranking(take_top(ranked_chara, top), ranks),
+        [tag] => {
+            let (tag_ranked, tag_ranks) = calculate_tag_ranking(characters, tag);
+            list_ranking(take_top(&tag_ranked, top), &tag_ranks);
+        }
+        _ => {
+            let boards: Vec<_> = tags
+                .iter()
+                .map(|tag| {
+                    let (tag_ranked, tag_ranks) = calculate_tag_ranking(characters, tag);
+                    (tag.clone(), tag_ranked, tag_ranks)
+                })
+                .collect();
+            display::list_tag_rankings(&boards, top.unwrap_or(usize::MAX));
+        }
+    }
+}
+
+fn take_top(ranked_chara: &[Character], top: Option<usize>) -> &[Character] {
+    let n = top.unwrap_or(ranked_chara.len()).min(ranked_chara.len());
+    &ranked_chara[..n]
+}
+
+// Look up a character by ID or by name, returns its position in characters
+fn find_character(
+    key: &str,
+    characters: &[Character],
+    name_to_id: &HashMap<String, usize>,
+) -> Option<usize> {
+    let by_id = |id: usize| characters.iter().position(|c| c.id == id);
+    if let Some(i) = key.parse::<usize>().ok().and_then(by_id) {
+        return Some(i);
+    }
+    by_id(*name_to_id.get(key)?)
+}
+
+// The character or the pair of an unknown mark, for messages
+fn unknown_mark(characters: &[Character], id: usize, other: Option<usize>) -> String {
+    let label = |id: usize| characters.iter().find(|c| c.id == id).unwrap().label();
+    match other {
+        None => label(id).to_string(),
+        Some(other) => format!("{} vs {}", label(id), label(other)),
+    }
+}
+
+fn find_or_exit(key: &str, characters: &[Character], name_to_id: &HashMap<String, usize>) -> usize {
+    match find_character(key, characters, name_to_id) {
+        Some(i) => i,
+        None => {
+            eprintln!("Error: no character named or numbered {}", key);
+            exit(EXIT_FAILURE);
+        }
+    }
 }
 
 fn handle_stat(
@@ -63,22 +592,8 @@ fn handle_stat(
     let mut ch = choice.split_off(4);
     ch = ch.trim().to_string();
 
-    match ch.parse::<usize>() {
-        Ok(id) => {
-            stat(&characters[id], characters, name_to_id, ranked_chara, ranks);
-            return;
-        }
-        Err(_) => {}
-    }
-
-    match name_to_id.get(&ch) {
-        Some(id) => stat(
-            &characters[*id],
-            &characters,
-            &name_to_id,
-            &ranked_chara,
-            &ranks,
-        ),
+    match find_character(&ch, characters, name_to_id) {
+        Some(id) => stat(&characters[id], characters, name_to_id, ranked_chara, ranks),
         None => {
             display::lobby_stat_help();
         }
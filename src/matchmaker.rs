@@ -0,0 +1,368 @@
+use crate::{
+    glicko::expected_information,
+    structs::{index_by_id, Character},
+};
+use rand::{distributions::WeightedIndex, prelude::*};
+use rand_chacha::ChaCha8Rng;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Chooses the next pair of a session
+pub trait Matchmaker {
+    // The positions in pool of the two characters of the next battle,
+    // None if fewer than two characters can battle. All randomness comes
+    // from rng, so a seeded rng gives reproducible sessions
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)>;
+}
+
+// The names accepted by matchmaker_by_name, the first one is the default
+pub const MATCHMAKERS: [&str; 6] = [
+    "weighted",
+    "random",
+    "neighbour",
+    "uncertain",
+    "info",
+    "swiss",
+];
+
+// The largest battle count difference that still changes the weights,
+// 4^20 already makes a character practically certain to be picked
+const MAX_WEIGHT_EXP: usize = 20;
+
+pub fn matchmaker_by_name(name: &str) -> Option<Box<dyn Matchmaker>> {
+    match name {
+        "weighted" => Some(Box::new(Weighted)),
+        "random" => Some(Box::new(Uniform)),
+        "neighbour" => Some(Box::new(Neighbour)),
+        "uncertain" => Some(Box::new(Uncertain)),
+        "info" => Some(Box::new(Informative)),
+        "swiss" => Some(Box::new(Swiss::new())),
+        _ => None,
+    }
+}
+
+// The random source of a session, seeded to reproduce its pairings. ChaCha8
+// gives the same stream on every platform and version, unlike StdRng
+pub fn session_rng(seed: Option<u64>) -> ChaCha8Rng {
+    match seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    }
+}
+
+// Positions of the recent opponents of a character
+fn recent_oppos(
+    pool: &[Character],
+    chara: &Character,
+    name_to_id: &HashMap<String, usize>,
+) -> HashSet<usize> {
+    let index = index_by_id(pool);
+    chara
+        .hist
+        .recent
+        .iter()
+        .filter_map(|b| name_to_id.get(&b.oppo).and_then(|id| index.get(id)))
+        .copied()
+        .collect()
+}
+
+// Positions of the characters which may battle
+fn matchable(pool: &[Character]) -> Vec<usize> {
+    (0..pool.len()).filter(|i| pool[*i].matchable()).collect()
+}
+
+// Positions of the characters which have someone to battle, that is
+// a matchable character they do not avoid
+fn pairable(pool: &[Character]) -> Vec<usize> {
+    let candidates = matchable(pool);
+    candidates
+        .iter()
+        .copied()
+        .filter(|&a| {
+            candidates
+                .iter()
+                .any(|&b| a != b && !pool[a].avoids(&pool[b]))
+        })
+        .collect()
+}
+
+// The possible opponents of first, never the ones it avoids, and avoiding
+// its recent opponents unless there is nobody else
+fn opponents(
+    pool: &[Character],
+    candidates: &[usize],
+    first: usize,
+    name_to_id: &HashMap<String, usize>,
+) -> Vec<usize> {
+    let oppos = recent_oppos(pool, &pool[first], name_to_id);
+    let allowed: Vec<usize> = candidates
+        .iter()
+        .copied()
+        .filter(|i| *i != first && !pool[first].avoids(&pool[*i]))
+        .collect();
+    let others: Vec<usize> = allowed
+        .iter()
+        .copied()
+        .filter(|i| !oppos.contains(i))
+        .collect();
+    if !others.is_empty() {
+        return others;
+    }
+    allowed
+}
+
+// Sample one of the candidates by weight, uniformly if all weights are zero
+fn sample(candidates: &[usize], weights: &[f64], rng: &mut dyn RngCore) -> Option<usize> {
+    let w: Vec<f64> = candidates.iter().map(|i| weights[*i]).collect();
+    match WeightedIndex::new(&w) {
+        Ok(distribution) => Some(candidates[distribution.sample(rng)]),
+        Err(_) => candidates.choose(rng).copied(),
+    }
+}
+
+// Randomize which character is shown on the left
+fn shuffle_sides(pair: (usize, usize), rng: &mut dyn RngCore) -> (usize, usize) {
+    if rng.gen() {
+        (pair.1, pair.0)
+    } else {
+        pair
+    }
+}
+
+// The pair of characters with the highest score, preferring characters
+// who did not meet recently
+fn best_pair<F>(
+    pool: &[Character],
+    name_to_id: &HashMap<String, usize>,
+    rng: &mut dyn RngCore,
+    score: F,
+) -> Option<(usize, usize)>
+where
+    F: Fn(&Character, &Character) -> f64,
+{
+    let mut best = None;
+    // (not met recently, score)
+    let mut best_score = (false, f64::NEG_INFINITY);
+    for (i, a) in pool.iter().enumerate() {
+        if !a.matchable() {
+            continue;
+        }
+        let oppos = recent_oppos(pool, a, name_to_id);
+        for (j, b) in pool.iter().enumerate().skip(i + 1) {
+            if !b.matchable() || a.avoids(b) {
+                continue;
+            }
+            let s = (!oppos.contains(&j), score(a, b));
+            if s > best_score {
+                best_score = s;
+                best = Some((i, j));
+            }
+        }
+    }
+    best.map(|pair| shuffle_sides(pair, rng))
+}
+
+// Prefer characters with fewer battles
+pub struct Weighted;
+
+impl Matchmaker for Weighted {
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)> {
+        let candidates = pairable(pool);
+        if candidates.len() < 2 {
+            return None;
+        }
+        let max = candidates
+            .iter()
+            .map(|i| pool[*i].hist.battles())
+            .max()
+            .unwrap();
+
+        // Calculate the weights (inverse of the battles), the characters with
+        // the most battles are only picked when nobody else is left
+        let weights: Vec<_> = pool
+            .iter()
+            .map(|c: &Character| {
+                if !c.matchable() || max == c.hist.battles() {
+                    0.0
+                } else {
+                    4f64.powi((max - c.hist.battles()).min(MAX_WEIGHT_EXP) as i32)
+                }
+            })
+            .collect();
+
+        // Select two distinct indices
+        let first = sample(&candidates, &weights, rng)?;
+        let second = sample(
+            &opponents(pool, &candidates, first, name_to_id),
+            &weights,
+            rng,
+        )?;
+
+        Some((first, second))
+    }
+}
+
+// Any two characters with the same chance
+pub struct Uniform;
+
+impl Matchmaker for Uniform {
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)> {
+        let candidates = pairable(pool);
+        if candidates.len() < 2 {
+            return None;
+        }
+        let first = *candidates.choose(rng)?;
+        let second = *opponents(pool, &candidates, first, name_to_id).choose(rng)?;
+
+        Some((first, second))
+    }
+}
+
+// A random character against the one with the closest rating
+pub struct Neighbour;
+
+impl Matchmaker for Neighbour {
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)> {
+        let candidates = pairable(pool);
+        if candidates.len() < 2 {
+            return None;
+        }
+        let first = *candidates.choose(rng)?;
+
+        let mut others = opponents(pool, &candidates, first, name_to_id);
+        // Shuffle first so characters with equal ratings take turns
+        others.shuffle(rng);
+        let rati = pool[first].rank.rati;
+        let second = *others.iter().min_by(|a, b| {
+            let da = (pool[**a].rank.rati - rati).abs();
+            let db = (pool[**b].rank.rati - rati).abs();
+            da.total_cmp(&db)
+        })?;
+
+        Some(shuffle_sides((first, second), rng))
+    }
+}
+
+// The two most uncertain characters
+pub struct Uncertain;
+
+impl Matchmaker for Uncertain {
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)> {
+        best_pair(pool, name_to_id, rng, |a, b| a.rank.devi + b.rank.devi)
+    }
+}
+
+// The pair whose result tells the most about the ratings
+pub struct Informative;
+
+impl Matchmaker for Informative {
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)> {
+        best_pair(pool, name_to_id, rng, |a, b| {
+            expected_information(&a.rank, &b.rank)
+        })
+    }
+}
+
+// Rounds in which every character battles once against a neighbour in the
+// ranking, a new round is drawn when the previous one is finished
+pub struct Swiss {
+    round: VecDeque<(usize, usize)>,
+}
+
+impl Swiss {
+    pub fn new() -> Self {
+        Self {
+            round: VecDeque::new(),
+        }
+    }
+
+    fn draw_round(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) {
+        let mut order = matchable(pool);
+        order.shuffle(rng);
+        order.sort_by(|a, b| pool[*b].rank.rati.total_cmp(&pool[*a].rank.rati));
+
+        let mut paired = vec![false; pool.len()];
+        for (k, &a) in order.iter().enumerate() {
+            if paired[a] {
+                continue;
+            }
+            let oppos = recent_oppos(pool, &pool[a], name_to_id);
+            let unpaired: Vec<usize> = order[k + 1..]
+                .iter()
+                .copied()
+                .filter(|b| !paired[*b] && !pool[a].avoids(&pool[*b]))
+                .collect();
+            // The closest one who was not met recently, or just the closest one
+            let Some(&b) = unpaired
+                .iter()
+                .find(|b| !oppos.contains(b))
+                .or(unpaired.first())
+            else {
+                continue;
+            };
+            paired[a] = true;
+            paired[b] = true;
+            self.round.push_back(shuffle_sides((a, b), rng));
+        }
+    }
+}
+
+impl Default for Swiss {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Matchmaker for Swiss {
+    fn pick(
+        &mut self,
+        pool: &[Character],
+        name_to_id: &HashMap<String, usize>,
+        rng: &mut dyn RngCore,
+    ) -> Option<(usize, usize)> {
+        // Drop pairs which cannot battle any more
+        self.round
+            .retain(|(a, b)| match (pool.get(*a), pool.get(*b)) {
+                (Some(a), Some(b)) => a.matchable() && b.matchable() && !a.avoids(b),
+                _ => false,
+            });
+        if self.round.is_empty() {
+            self.draw_round(pool, name_to_id, rng);
+        }
+        self.round.pop_front()
+    }
+}
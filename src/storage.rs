@@ -0,0 +1,644 @@
+use glicko2::{
+    battle::Session,
+    config::GlickoConfig,
+    structs::{name_to_id_map, unix_now, Character, LogEntry, Match, Meta, Snapshot},
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process::exit,
+};
+
+// File names inside a pool directory
+const DATA_FILE: &str = "data.json";
+const INIT_FILE: &str = "init.txt";
+const ROSTER_FILE: &str = "roster.json";
+const CONFIG_FILE: &str = "config.json";
+const LOG_FILE: &str = "matches.jsonl";
+const SESSION_FILE: &str = "session.json";
+const SNAPSHOT_FILE: &str = "snapshots.jsonl";
+
+// The number of old data files kept as data.json.1 (newest) .. data.json.N
+const MAX_BACKUPS: usize = 5;
+// The number of latest sessions which can be rolled back
+const MAX_SNAPSHOTS: usize = 20;
+
+// Environment variable selecting the pool when --pool is not given
+pub const POOL_ENV: &str = "GLICKO2_POOL";
+const DEFAULT_POOL: &str = "default";
+// Where versions without pools kept their files, relative to the working directory
+const LEGACY_DIR: &str = "src";
+
+// A directory holding one independent ranking: its roster, data and config
+pub struct Pool {
+    pub dir: PathBuf,
+}
+
+impl Pool {
+    // Select a pool by name or path, falling back to $GLICKO2_POOL and then
+    // the default pool. Values containing a path separator are paths,
+    // anything else is the name of a pool in the user's data directory
+    pub fn resolve(arg: Option<&str>) -> Result<Self, String> {
+        let value = match arg {
+            Some(v) => v.to_string(),
+            None => env::var(POOL_ENV).unwrap_or_else(|_| DEFAULT_POOL.to_string()),
+        };
+        let is_default = value == DEFAULT_POOL;
+        if value.is_empty() {
+            return Err("pool name cannot be empty".to_string());
+        }
+        if value.contains(std::path::is_separator) {
+            return Ok(Self {
+                dir: PathBuf::from(value),
+            });
+        }
+        let home =
+            data_home().ok_or("cannot find the data directory, set $XDG_DATA_HOME or $HOME")?;
+        let pool = Self {
+            dir: home.join("glicko2").join(value),
+        };
+        if is_default {
+            pool.import_legacy_files()?;
+        }
+        Ok(pool)
+    }
+
+    // Copy the roster and data of a version without pools into a new default
+    // pool, so an existing ranking carries over on the first run
+    fn import_legacy_files(&self) -> Result<(), String> {
+        let legacy = Path::new(LEGACY_DIR);
+        let legacy_init = legacy.join(INIT_FILE);
+        if !legacy_init.exists() || self.init_path().exists() || self.data_path().exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir).map_err(|e| format!("{}: {}", self.dir.display(), e))?;
+        for file in [INIT_FILE, DATA_FILE] {
+            let from = legacy.join(file);
+            if !from.exists() {
+                continue;
+            }
+            fs::copy(&from, self.dir.join(file))
+                .map_err(|e| format!("{}: {}", from.display(), e))?;
+            eprintln!(
+                "Imported {} into the default pool {}",
+                from.display(),
+                self.dir.display()
+            );
+        }
+        Ok(())
+    }
+    pub fn data_path(&self) -> PathBuf {
+        self.dir.join(DATA_FILE)
+    }
+    // The structured roster if there is one, the plain name list otherwise
+    pub fn init_path(&self) -> PathBuf {
+        let roster = self.dir.join(ROSTER_FILE);
+        if roster.exists() {
+            roster
+        } else {
+            self.dir.join(INIT_FILE)
+        }
+    }
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.join(CONFIG_FILE)
+    }
+    pub fn log_path(&self) -> PathBuf {
+        self.dir.join(LOG_FILE)
+    }
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(SNAPSHOT_FILE)
+    }
+
+    pub fn session_path(&self) -> PathBuf {
+        self.dir.join(SESSION_FILE)
+    }
+
+    pub fn backup_path(&self, i: usize) -> PathBuf {
+        self.dir.join(format!("{}.{}", DATA_FILE, i))
+    }
+}
+
+// $XDG_DATA_HOME, or ~/.local/share as the XDG spec suggests
+fn data_home() -> Option<PathBuf> {
+    if let Ok(dir) = env::var("XDG_DATA_HOME") {
+        let dir = PathBuf::from(dir);
+        if dir.is_absolute() {
+            return Some(dir);
+        }
+    }
+    let home = env::var("HOME").ok().filter(|h| !h.is_empty())?;
+    Some(PathBuf::from(home).join(".local").join("share"))
+}
+
+// Load the characters of a pool, merging the roster into the data. Only an
+// interactive loader asks questions, all messages go to stderr so the
+// output of the other commands stays clean
+pub fn initialize_characters(
+    pool: &Pool,
+    config: &GlickoConfig,
+    interactive: bool,
+) -> (Vec<Character>, HashMap<String, usize>) {
+    // Try read data from file
+    let mut init = read_init_characters(pool, config);
+    let mut read = load_characters(pool, interactive);
+    if read.is_empty() {
+        eprintln!("Initialize from {}", pool.init_path().display());
+        let now = unix_now();
+        for c in init.iter_mut() {
+            c.since = now;
+        }
+        let name_to_id = name_to_id_map(&init);
+        return (init, name_to_id);
+    }
+
+    eprintln!("Read data from {}", pool.data_path().display());
+    // IDs are kept in the data file once assigned, so the order of the
+    // roster does not matter. New characters get IDs which were never used
+    let mut next_id = next_free_id(pool, &read);
+    fix_duplicate_ids(&mut read, &mut next_id);
+    let read_len = read.len();
+
+    // A roster line may use the current name or any alias of a character
+    let mut init_names: HashSet<String> = HashSet::new();
+    let mut read_names: HashSet<String> = HashSet::new();
+    for c in read.iter() {
+        read_names.extend(c.names().cloned());
+    }
+    for c in init.iter() {
+        init_names.insert(c.name.clone());
+        if !read_names.contains(&c.name) {
+            let mut chara = Character::new(next_id, c.name.clone(), config);
+            chara.meta = c.meta.clone();
+            // Sessions before this one went on without the character
+            chara.since = unix_now();
+            read.push(chara);
+            next_id += 1;
+        }
+    }
+    // The roster is the source of the metadata
+    let init_meta: HashMap<&String, &Meta> = init.iter().map(|c| (&c.name, &c.meta)).collect();
+    for c in read[..read_len].iter_mut() {
+        let meta = c.names().find_map(|n| init_meta.get(n)).cloned();
+        if let Some(meta) = meta {
+            c.meta = meta.clone();
+        }
+    }
+
+    if read.len() != read_len {
+        eprintln!("{:-<1$}", "", 36);
+        eprintln!("Find new characters in {}", pool.init_path().display());
+        for c in read[read_len..].iter() {
+            eprintln!("#{}: {}", c.id, c.name);
+        }
+    }
+
+    read.sort_by_key(|c| c.id);
+    let in_roster = |c: &Character| c.names().any(|n| init_names.contains(n));
+    if !read.iter().all(in_roster) {
+        eprintln!("{:-<1$}", "", 36);
+        eprintln!("Find some characters not in {}", pool.init_path().display());
+        for c in read.iter().filter(|c| !in_roster(c)) {
+            eprintln!("#{}: {}", c.id, c.name);
+        }
+        if !interactive {
+            eprintln!("Keep them, start the lobby to remove them.");
+        } else {
+            eprint!("Do you want to REMOVE them ? (Y/n) ");
+            let mut choice: String = String::new();
+            let _ = io::stderr().flush();
+            let _ = io::stdin().read_line(&mut choice);
+            if choice.to_uppercase().starts_with("Y") {
+                read.retain(in_roster);
+                eprintln!("Remove Success!");
+            } else {
+                eprintln!("Keep them exists.");
+            }
+        }
+    }
+
+    let name_to_id = name_to_id_map(&read);
+    (read, name_to_id)
+}
+
+// Older versions could give two characters the same ID, which would make
+// them share one slot. The first one keeps the ID, the others get new ones
+fn fix_duplicate_ids(characters: &mut [Character], next_id: &mut usize) {
+    let mut seen: HashSet<usize> = HashSet::new();
+    for c in characters.iter_mut() {
+        if !seen.insert(c.id) {
+            eprintln!(
+                "#{}: {} has the ID of another character, it becomes #{}",
+                c.id, c.name, *next_id
+            );
+            c.id = *next_id;
+            *next_id += 1;
+        }
+    }
+}
+
+// The lowest ID above those of the characters and of every character in the
+// match log and the snapshots, so a removed character's ID is never given to
+// another one. Unreadable lines are left to the commands reading the files
+fn next_free_id(pool: &Pool, characters: &[Character]) -> usize {
+    let mut next_id = characters.iter().map(|c| c.id + 1).max().unwrap_or(0);
+    let read_lines = |path: PathBuf| fs::read_to_string(path).unwrap_or_default();
+    for l in read_lines(pool.log_path()).lines() {
+        if let Ok(entry) = serde_json::from_str::<LogEntry>(l) {
+            next_id = next_id.max(entry.game.a.max(entry.game.b) + 1);
+        }
+    }
+    for l in read_lines(pool.snapshot_path()).lines() {
+        if let Ok(snapshot) = serde_json::from_str::<Snapshot>(l) {
+            for (id, _, _) in snapshot.states.iter() {
+                next_id = next_id.max(id + 1);
+            }
+        }
+    }
+    next_id
+}
+
+// A line of the structured roster, either just a name or a name with metadata
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RosterEntry {
+    Name(String),
+    Full {
+        name: String,
+        #[serde(flatten)]
+        meta: Meta,
+    },
+}
+
+pub fn read_init_characters(pool: &Pool, config: &GlickoConfig) -> Vec<Character> {
+    let path = pool.init_path();
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) => {
+            eprintln!("\nError: {}: {}", path.display(), error);
+            eprintln!(
+                "Create {} (one name per line) or {}, or select a pool with --pool.",
+                INIT_FILE, ROSTER_FILE
+            );
+            eprintln!(
+                "Files of older versions are found with --pool ./{}, they are imported \
+                 into the default pool when run from the directory containing {}/{}.",
+                LEGACY_DIR, LEGACY_DIR, INIT_FILE
+            );
+            exit(1);
+        }
+    };
+
+    let entries: Vec<(String, Meta)> = if path.ends_with(ROSTER_FILE) {
+        match serde_json::from_str::<Vec<RosterEntry>>(&content) {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|entry| match entry {
+                    RosterEntry::Name(name) => (name, Meta::new()),
+                    RosterEntry::Full { name, meta } => (name, meta),
+                })
+                .collect(),
+            Err(error) => {
+                eprintln!("\nError: {}: {}", path.display(), error);
+                exit(1);
+            }
+        }
+    } else {
+        content
+            .lines()
+            .map(|l| (l.to_string(), Meta::new()))
+            .collect()
+    };
+
+    let mut characters: Vec<Character> = Vec::new();
+    for (name, meta) in entries {
+        let name = name.trim().to_owned();
+        if name.is_empty() {
+            continue;
+        }
+        // Only used for a new pool, IDs are stored with the data afterwards
+        let mut chara = Character::new(characters.len(), name, config);
+        chara.meta = meta;
+        characters.push(chara);
+    }
+
+    characters
+}
+
+// A data file which exists but cannot be loaded
+pub enum DataError {
+    Io {
+        path: PathBuf,
+        error: io::Error,
+    },
+    // the error carries the line and column of the problem
+    Json {
+        path: PathBuf,
+        error: serde_json::Error,
+    },
+}
+
+impl fmt::Display for DataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataError::Io { path, error } => write!(f, "{}: {}", path.display(), error),
+            DataError::Json { path, error } => {
+                write!(f, "{}: broken data file, {}", path.display(), error)
+            }
+        }
+    }
+}
+
+// Read characters from a data file, a missing file holds no characters
+pub fn read_characters(path: &Path) -> Result<Vec<Character>, DataError> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => {
+            return Err(DataError::Io {
+                path: path.to_path_buf(),
+                error,
+            })
+        }
+    };
+    // Deserialize from json string
+    serde_json::from_str(&content).map_err(|error| DataError::Json {
+        path: path.to_path_buf(),
+        error,
+    })
+}
+
+// Load the data of a pool, asking how to recover when the file is broken
+fn load_characters(pool: &Pool, interactive: bool) -> Vec<Character> {
+    match read_characters(&pool.data_path()) {
+        Ok(characters) => characters,
+        Err(error) => {
+            eprintln!("\nError: {}", error);
+            if !interactive {
+                eprintln!("Start the lobby to recover from a backup.");
+                exit(1);
+            }
+            recover_characters(pool)
+        }
+    }
+}
+
+fn recover_characters(pool: &Pool) -> Vec<Character> {
+    // Find the newest backup which can be loaded
+    let backup = (1..=MAX_BACKUPS)
+        .filter(|i| pool.backup_path(*i).exists())
+        .find_map(|i| match read_characters(&pool.backup_path(i)) {
+            Ok(characters) => Some((pool.backup_path(i), characters)),
+            Err(error) => {
+                eprintln!("Skip backup {}", error);
+                None
+            }
+        });
+    let Some((path, characters)) = backup else {
+        eprintln!("No usable backup, the data file is left untouched.");
+        exit(1);
+    };
+
+    let corrupt_path = pool.data_path().with_extension("json.corrupt");
+    eprintln!("{:-<1$}", "", 36);
+    eprintln!("1. Load the latest backup {}", path.display());
+    eprintln!("   (the broken file is kept as {})", corrupt_path.display());
+    eprintln!("2. Abort without touching any file");
+    eprint!("Choose [1/2] (default 2) >> ");
+    let mut choice: String = String::new();
+    let _ = io::stderr().flush();
+    let _ = io::stdin().read_line(&mut choice);
+    if choice.trim() != "1" {
+        eprintln!("Abort.");
+        exit(1);
+    }
+
+    // Move the broken file away, so the next save cannot rotate it into the backups
+    if let Err(error) = fs::rename(pool.data_path(), &corrupt_path) {
+        eprintln!("\nError: {}: {}", corrupt_path.display(), error);
+        exit(1);
+    }
+    // The backup becomes the data at once, whether or not anything is saved later
+    if let Err(error) = fs::copy(&path, pool.data_path()) {
+        eprintln!("\nError: {}: {}", pool.data_path().display(), error);
+        let _ = fs::rename(&corrupt_path, pool.data_path());
+        exit(1);
+    }
+    eprintln!("Loaded {}", path.display());
+    characters
+}
+
+pub fn store_characters(pool: &Pool, characters: &[Character]) -> io::Result<()> {
+    // Serialize to json string
+    let serialized = serde_json::to_string(&characters)?;
+    fs::create_dir_all(&pool.dir)?;
+
+    // Write the whole file aside first, so a crash never leaves a partial file
+    let tmp_path = pool.data_path().with_extension("json.tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(serialized.as_bytes())?;
+    file.sync_all()?;
+
+    rotate_backups(pool)?;
+    fs::rename(&tmp_path, pool.data_path())
+}
+
+// Whether the data file holds exactly these characters
+pub fn is_stored(pool: &Pool, characters: &[Character]) -> bool {
+    let Ok(stored) = fs::read_to_string(pool.data_path()) else {
+        return false;
+    };
+    serde_json::to_string(characters).is_ok_and(|current| current == stored)
+}
+
+// Shift data.json.1 .. data.json.N by one and copy the current data to
+// data.json.1, dropping the oldest snapshot
+fn rotate_backups(pool: &Pool) -> io::Result<()> {
+    if !pool.data_path().exists() {
+        return Ok(());
+    }
+    for i in (1..MAX_BACKUPS).rev() {
+        if pool.backup_path(i).exists() {
+            fs::rename(pool.backup_path(i), pool.backup_path(i + 1))?;
+        }
+    }
+    fs::copy(pool.data_path(), pool.backup_path(1))?;
+    Ok(())
+}
+
+pub fn read_match_log(pool: &Pool) -> Result<Vec<LogEntry>, String> {
+    read_json_lines(&pool.log_path())
+}
+
+// Read a file of one json value per line, a missing file has no values.
+// A broken last line without a line break is what a crash in the middle of
+// an append leaves behind, it is skipped instead of failing
+fn read_json_lines<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(format!("{}: {}", path.display(), error)),
+    };
+
+    let mut entries: Vec<T> = Vec::new();
+    let count = content.lines().count();
+    for (i, l) in content.lines().enumerate() {
+        if l.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(l) {
+            Ok(entry) => entries.push(entry),
+            Err(_) if i + 1 == count && !content.ends_with('\n') => {
+                eprintln!("Skip the partial last line of {}", path.display());
+            }
+            Err(error) => return Err(format!("{}:{}: {}", path.display(), i + 1, error)),
+        }
+    }
+
+    Ok(entries)
+}
+
+// The last complete line of a file, read from the end. A partial line left
+// by a crash in the middle of an append is cut off the file
+fn last_line(file: &mut File, path: &Path) -> io::Result<Option<String>> {
+    const CHUNK: u64 = 4096;
+    let len = file.metadata()?.len();
+    // Read backwards until the tail holds the two last line breaks
+    let mut start = len;
+    let mut tail: Vec<u8> = Vec::new();
+    while start > 0 && tail.iter().filter(|b| **b == b'\n').count() < 2 {
+        let from = start.saturating_sub(CHUNK);
+        let mut chunk = vec![0; (start - from) as usize];
+        file.seek(SeekFrom::Start(from))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        start = from;
+    }
+
+    let end = tail.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+    if end < tail.len() {
+        eprintln!("Drop the partial last line of {}", path.display());
+        file.set_len(start + end as u64)?;
+    }
+    let complete = &tail[..end.saturating_sub(1)];
+    let begin = complete
+        .iter()
+        .rposition(|b| *b == b'\n')
+        .map_or(0, |i| i + 1);
+    let line = String::from_utf8_lossy(&complete[begin..])
+        .trim()
+        .to_string();
+    Ok(Some(line).filter(|l| !l.is_empty()))
+}
+
+// Append the matches of a finished session to the match log,
+// returns the id given to the session
+pub fn append_match_log(pool: &Pool, records: &[Match], time: u64) -> io::Result<usize> {
+    fs::create_dir_all(&pool.dir)?;
+    let path = pool.log_path();
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .read(true)
+        .write(true)
+        .open(&path)?;
+
+    // Only the last entry is needed to number the session
+    let session = match last_line(&mut file, &path)? {
+        Some(line) => {
+            let last: LogEntry = serde_json::from_str(&line).map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: {}", path.display(), e),
+                )
+            })?;
+            last.session + 1
+        }
+        None => 0,
+    };
+
+    let mut lines = String::new();
+    for m in records.iter() {
+        let entry = LogEntry::new(session, time, m.clone());
+        lines.push_str(&serde_json::to_string(&entry)?);
+        lines.push('\n');
+    }
+
+    // A single write keeps the lines of a session together
+    file.seek(SeekFrom::End(0))?;
+    file.write_all(lines.as_bytes())?;
+    file.sync_all()?;
+
+    Ok(session)
+}
+
+// Replace a file of one json value per line, never leaving a partial file
+fn write_json_lines<T: Serialize>(path: &Path, values: &[T]) -> io::Result<()> {
+    let mut lines = String::new();
+    for value in values.iter() {
+        lines.push_str(&serde_json::to_string(value)?);
+        lines.push('\n');
+    }
+    let tmp_path = path.with_extension("jsonl.tmp");
+    fs::write(&tmp_path, lines)?;
+    fs::rename(&tmp_path, path)
+}
+
+// Drop the given session and all later ones from the match log
+pub fn truncate_match_log(pool: &Pool, session: usize) -> io::Result<()> {
+    let mut entries = read_match_log(pool).map_err(io::Error::other)?;
+    entries.retain(|entry| entry.session < session);
+    write_json_lines(&pool.log_path(), &entries)
+}
+
+// The snapshots of the latest sessions, oldest first
+pub fn read_snapshots(pool: &Pool) -> Result<Vec<Snapshot>, String> {
+    read_json_lines(&pool.snapshot_path())
+}
+
+// Replace the snapshots, keeping only the latest ones
+pub fn write_snapshots(pool: &Pool, snapshots: &[Snapshot]) -> io::Result<()> {
+    fs::create_dir_all(&pool.dir)?;
+    let first = snapshots.len().saturating_sub(MAX_SNAPSHOTS);
+    write_json_lines(&pool.snapshot_path(), &snapshots[first..])
+}
+
+// Keep the session in progress on disk, replacing the previous state
+pub fn write_session_journal(pool: &Pool, session: &Session) -> io::Result<()> {
+    let serialized = serde_json::to_string(session)?;
+    fs::create_dir_all(&pool.dir)?;
+    let tmp_path = pool.session_path().with_extension("json.tmp");
+    fs::write(&tmp_path, serialized)?;
+    fs::rename(&tmp_path, pool.session_path())
+}
+
+// The session interrupted before it was applied, if any
+pub fn read_session_journal(pool: &Pool) -> Option<Session> {
+    let content = match fs::read_to_string(pool.session_path()) {
+        Ok(content) => content,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return None,
+        Err(error) => {
+            eprintln!("\nError: {}: {}", pool.session_path().display(), error);
+            return None;
+        }
+    };
+    match serde_json::from_str(&content) {
+        Ok(session) => Some(session),
+        Err(error) => {
+            eprintln!("\nError: {}: {}", pool.session_path().display(), error);
+            None
+        }
+    }
+}
+
+pub fn remove_session_journal(pool: &Pool) {
+    if let Err(error) = fs::remove_file(pool.session_path()) {
+        if error.kind() != io::ErrorKind::NotFound {
+            eprintln!("\nError: {}: {}", pool.session_path().display(), error);
+        }
+    }
+}
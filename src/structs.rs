@@ -1,36 +1,41 @@
+use crate::config::{GlickoConfig, GLICKO2_CENTER, GLICKO2_SCALE};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
-    fs::{self, File},
-    io::{self, BufRead, BufReader, Write},
-    process::exit,
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-pub enum BattleStat {
-    Next,
-    End,
-    Undo,
-}
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum MatchResult {
     AWin,
     BWin,
     Draw,
     BothLose,
+    AWeakWin, // a is only slightly preferred
+    BWeakWin,
 }
 
 // A matchup between two characters
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Match {
     pub a: usize,         // the id of first character
     pub b: usize,         // the id of first character
     pub res: MatchResult, // result of the match
 }
 
+// A match as stored in the append-only match log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogEntry {
+    pub session: usize, // the rating session the match belongs to
+    pub time: u64,      // unix time the session was applied
+    #[serde(flatten)]
+    pub game: Match,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Battle {
-    pub oppo: String, // opponent's name
+    pub oppo: String,     // opponent's name
     pub res: MatchResult, // AWins for I win, BWins for I lose
 }
 
@@ -39,6 +44,8 @@ pub struct Rank {
     pub rati: f64, // rating
     pub devi: f64, // rating deviation
     pub vola: f64, // rating volatility
+    #[serde(default)]
+    pub time: u64, // unix time of the last update, 0 if never rated
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -53,10 +60,44 @@ pub struct History {
     pub recent: VecDeque<Battle>,
 }
 
+// The ranks, histories and unknown marks of all characters before a session was applied
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub session: usize, // the session applied after the snapshot
+    pub time: u64,      // unix time the session was applied
+    pub matches: usize, // number of matches of the session
+    pub states: Vec<(usize, Rank, History)>,
+    // the unknown marks of every character, missing in older snapshots
+    #[serde(default)]
+    pub marks: Vec<(usize, bool, Vec<usize>)>,
+}
+
+// Extra information about a character, given by the roster
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Meta {
+    pub display: Option<String>, // name shown instead of the real one
+    pub tags: Vec<String>,       // e.g. class, rarity, faction
+    pub image: Option<String>,   // path or URL of a picture
+    pub active: bool,            // inactive characters are not matched
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Character {
     pub id: usize,
     pub name: String,
+    #[serde(default)]
+    pub aliases: Vec<String>, // former or alternative names
+    #[serde(default)]
+    pub meta: Meta,
+    #[serde(default)]
+    pub excluded: bool, // kept out of battles by the user
+    #[serde(default)]
+    pub unknown: bool, // never picked, the user cannot judge it
+    #[serde(default)]
+    pub since: u64, // unix time it joined the pool, 0 if unknown
+    #[serde(default)]
+    pub avoid: Vec<usize>, // ids of opponents the user cannot judge it against
     pub rank: Rank,    // glicko ranking information
     pub hist: History, // historical stats
 }
@@ -71,6 +112,96 @@ impl Match {
     }
 }
 
+impl LogEntry {
+    pub fn new(session: usize, time: u64, game: Match) -> Self {
+        Self {
+            session: (session),
+            time: (time),
+            game: (game),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn take(session: usize, time: u64, matches: usize, characters: &[Character]) -> Self {
+        Self {
+            session: (session),
+            time: (time),
+            matches: (matches),
+            states: characters
+                .iter()
+                .map(|c| (c.id, c.rank.clone(), c.hist.clone()))
+                .collect(),
+            marks: characters
+                .iter()
+                .map(|c| (c.id, c.unknown, c.avoid.clone()))
+                .collect(),
+        }
+    }
+
+    // Put every character back to its state in the snapshot,
+    // characters added after the snapshot are left as they are
+    pub fn restore(&self, characters: &mut [Character]) {
+        let index = index_by_id(characters);
+        for (id, rank, hist) in self.states.iter() {
+            if let Some(&i) = index.get(id) {
+                characters[i].rank = rank.clone();
+                characters[i].hist = hist.clone();
+            }
+        }
+        for (id, unknown, avoid) in self.marks.iter() {
+            if let Some(&i) = index.get(id) {
+                characters[i].unknown = *unknown;
+                characters[i].avoid = avoid.clone();
+            }
+        }
+    }
+}
+
+impl FromStr for MatchResult {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "a-win" => Ok(MatchResult::AWin),
+            "b-win" => Ok(MatchResult::BWin),
+            "draw" => Ok(MatchResult::Draw),
+            "both-lose" => Ok(MatchResult::BothLose),
+            "a-weak-win" => Ok(MatchResult::AWeakWin),
+            "b-weak-win" => Ok(MatchResult::BWeakWin),
+            _ => Err(format!(
+                "unknown match result: {} (a-win, b-win, draw, both-lose, a-weak-win, b-weak-win)",
+                s
+            )),
+        }
+    }
+}
+
+impl MatchResult {
+    // The scores of both sides, a slight win scores weak_win
+    pub fn scores(&self, weak_win: f64) -> (f64, f64) {
+        match self {
+            MatchResult::AWin => (1.0, 0.0),
+            MatchResult::BWin => (0.0, 1.0),
+            MatchResult::Draw => (0.5, 0.5),
+            MatchResult::BothLose => (0.0, 0.0),
+            MatchResult::AWeakWin => (weak_win, 1.0 - weak_win),
+            MatchResult::BWeakWin => (1.0 - weak_win, weak_win),
+        }
+    }
+    // The same result seen from the side of b
+    pub fn swapped(&self) -> Self {
+        match self {
+            MatchResult::AWin => MatchResult::BWin,
+            MatchResult::BWin => MatchResult::AWin,
+            MatchResult::AWeakWin => MatchResult::BWeakWin,
+            MatchResult::BWeakWin => MatchResult::AWeakWin,
+            MatchResult::Draw => MatchResult::Draw,
+            MatchResult::BothLose => MatchResult::BothLose,
+        }
+    }
+}
+
 impl Battle {
     pub fn new(oppo: String, res: MatchResult) -> Self {
         Self {
@@ -81,20 +212,27 @@ impl Battle {
 }
 
 impl Rank {
-    pub fn new() -> Self {
+    pub fn new(config: &GlickoConfig) -> Self {
         Self {
-            rati: (1500.0),
-            devi: (350.0),
-            vola: (0.06),
+            rati: (config.init_rati),
+            devi: (config.init_devi),
+            vola: (config.init_vola),
+            time: (0),
         }
     }
     pub fn glicko_1_to_2_scale(&mut self) {
-        self.rati = (self.rati - 1500.0) / 173.7178;
-        self.devi = self.devi / 173.7178;
+        self.rati = (self.rati - GLICKO2_CENTER) / GLICKO2_SCALE;
+        self.devi /= GLICKO2_SCALE;
     }
     pub fn glicko_2_to_1_scale(&mut self) {
-        self.rati = self.rati * 173.7178 + 1500.0;
-        self.devi = self.devi * 173.7178;
+        self.rati = self.rati * GLICKO2_SCALE + GLICKO2_CENTER;
+        self.devi *= GLICKO2_SCALE;
+    }
+}
+
+impl Default for Rank {
+    fn default() -> Self {
+        Self::new(&GlickoConfig::default())
     }
 }
 
@@ -114,148 +252,163 @@ impl History {
     }
 }
 
-impl Character {
-    pub fn new(id: usize, name: String) -> Self {
+impl Meta {
+    pub fn new() -> Self {
         Self {
-            id: (id),
-            name: (name),
-            rank: Rank::new(),
-            hist: History::new(),
+            display: None,
+            tags: Vec::new(),
+            image: None,
+            active: (true),
         }
     }
 }
 
-const DATA_PATH: &str = "src/data.json";
-const INIT_PATH: &str = "src/init.txt";
+impl Default for Meta {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-pub fn initialize_characters() -> (Vec<Character>, HashMap<String, usize>) {
-    // Try read data from file
-    let init = read_init_characters();
-    let mut read = read_characters();
-    let mut name_to_id: HashMap<String, usize> = HashMap::new();
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    if read.is_empty() {
-        println!("Initialize from {}", INIT_PATH);
-        for c in init.iter() {
-            name_to_id.insert(c.name.clone(), c.id);
+impl Character {
+    pub fn new(id: usize, name: String, config: &GlickoConfig) -> Self {
+        Self {
+            id: (id),
+            name: (name),
+            aliases: Vec::new(),
+            meta: Meta::new(),
+            excluded: (false),
+            unknown: (false),
+            since: (0),
+            avoid: Vec::new(),
+            rank: Rank::new(config),
+            hist: History::new(),
         }
-        return (init, name_to_id);
     }
-
-    println!("Read data from {}", DATA_PATH);
-    let read_len = read.len();
-
-    let mut init_name_id: HashMap<String, usize> = HashMap::new();
-    let mut read_names: HashSet<String> = HashSet::new();
-    for c in read.iter() {
-        read_names.insert(c.name.clone());
+    // The name shown to the user
+    pub fn label(&self) -> &str {
+        self.meta.display.as_deref().unwrap_or(&self.name)
     }
-    for c in init.iter() {
-        init_name_id.insert(c.name.clone(), c.id);
-        if !read_names.contains(&c.name) {
-            read.push(c.clone());
-        }
+    // Whether the character may be picked for battles
+    pub fn matchable(&self) -> bool {
+        self.meta.active && !self.excluded && !self.unknown
     }
-
-    if read.len() != read_len {
-        println!("{:-<1$}", "", 36);
-        println!("Find new characters in {}", INIT_PATH);
-        for c in read[read_len..].iter() {
-            println!("#{}: {}", c.id, c.name);
-        }
+    // Whether the two characters must not be picked as a pair
+    pub fn avoids(&self, other: &Character) -> bool {
+        self.avoid.contains(&other.id) || other.avoid.contains(&self.id)
     }
-
-    let mut next_id = init.len();
-    for c in read.iter_mut() {
-        match init_name_id.get(&c.name) {
-            Some(id) => {
-                c.id = *id;
-            }
-            None => {
-                c.id = next_id;
-                next_id += 1;
-            }
-        }
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.meta.tags.iter().any(|t| t == tag)
+    }
+    // The current name followed by all aliases
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.name).chain(self.aliases.iter())
     }
+}
 
-    read.sort_by_key(|c| c.id);
-    if read.len() != init.len() {
-        println!("{:-<1$}", "", 36);
-        println!("Find some characters not in {}", INIT_PATH);
-        for c in read[init.len()..].iter() {
-            println!("#{}: {}", c.id, c.name);
-        }
-        print!("Do you want to REMOVE them ? (Y/n) ");
-        let mut choice: String = String::new();
-        let _ = io::stdout().flush();
-        let _ = io::stdin().read_line(&mut choice);
-        if choice.to_uppercase().starts_with("Y") {
-            let _ = read.split_off(init.len());
-            println!("Remove Success!");
-        } else {
-            println!("Keep them exists.");
+// Seconds since the unix epoch
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Map the ID of every character to its position in the slice
+pub fn index_by_id(characters: &[Character]) -> HashMap<usize, usize> {
+    characters
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c.id, i))
+        .collect()
+}
+
+// Map every name and alias to the ID of its character
+pub fn name_to_id_map(characters: &[Character]) -> HashMap<String, usize> {
+    let mut name_to_id: HashMap<String, usize> = HashMap::new();
+    for c in characters.iter() {
+        for name in c.names() {
+            name_to_id.insert(name.clone(), c.id);
         }
     }
+    name_to_id
+}
 
-    for c in read.iter() {
-        name_to_id.insert(c.name.clone(), c.id);
+fn check_name_free(characters: &[Character], id: usize, name: &str) -> Result<(), String> {
+    if name.trim().is_empty() || name.trim() != name {
+        return Err(format!("invalid name: '{}'", name));
+    }
+    match characters.iter().find(|c| c.names().any(|n| n == name)) {
+        Some(c) if c.id != id => Err(format!(
+            "'{}' is already used by #{} {}",
+            name, c.id, c.name
+        )),
+        _ => Ok(()),
     }
-
-    (read, name_to_id)
 }
 
-pub fn read_init_characters() -> Vec<Character> {
-    let mut characters: Vec<Character> = Vec::new();
-
-    // Initialize data from file
-    let file = match File::open(&INIT_PATH) {
-        Ok(f) => f,
-        Err(error) => {
-            eprintln!("\nError: {}", error);
-            exit(1);
-        }
-    };
-    let reader = BufReader::new(file);
-
-    for (id, line) in reader.lines().enumerate() {
-        match line {
-            Ok(l) => {
-                let _l = l.trim().to_owned();
-                if _l.is_empty() {
-                    continue;
-                }
-                let chara = Character::new(id, _l);
-                characters.push(chara);
-            }
-            Err(error) => {
-                eprintln!("\nError: {}", error);
-                exit(1);
+// Take back an unknown mark, of the character alone or of its pair with
+// another, returns whether there was such a mark
+pub fn mark_known(characters: &mut [Character], id: usize, other: Option<usize>) -> bool {
+    let mut marked = false;
+    for c in characters.iter_mut() {
+        match other {
+            None if c.id == id => marked = std::mem::take(&mut c.unknown),
+            Some(other) if c.id == id || c.id == other => {
+                let opponent = if c.id == id { other } else { id };
+                let len = c.avoid.len();
+                c.avoid.retain(|o| *o != opponent);
+                marked |= c.avoid.len() != len;
             }
+            _ => {}
         }
     }
-
-    characters
+    marked
 }
 
-pub fn read_characters() -> Vec<Character> {
-    // Read data from file
-    let result = fs::read_to_string(DATA_PATH);
-    match result {
-        Ok(content) => {
-            // Deserialize from json string
-            let objs = serde_json::from_str(&content);
-            match objs {
-                Ok(ret) => return ret,
-                Err(_) => return Vec::new(),
+// Rename a character, keeping the old name as an alias and
+// rewriting the recent battles of every opponent
+pub fn rename_character(
+    characters: &mut [Character],
+    id: usize,
+    new_name: &str,
+) -> Result<(), String> {
+    check_name_free(characters, id, new_name)?;
+    let chara = characters
+        .iter_mut()
+        .find(|c| c.id == id)
+        .ok_or_else(|| format!("no character #{}", id))?;
+    if chara.name == new_name {
+        return Ok(());
+    }
+
+    let old_name = std::mem::replace(&mut chara.name, new_name.to_string());
+    chara.aliases.retain(|a| a != new_name);
+    chara.aliases.push(old_name.clone());
+
+    for c in characters.iter_mut() {
+        for b in c.hist.recent.iter_mut() {
+            if b.oppo == old_name {
+                b.oppo = new_name.to_string();
             }
         }
-        Err(_) => return Vec::new(),
     }
+    Ok(())
 }
 
-pub fn store_characters(characters: &[Character]) {
-    // Serialize to json string
-    let serialized = serde_json::to_string(&characters).unwrap();
-    // Write string to file
-    fs::write(DATA_PATH, serialized).unwrap();
+// Let a character also be found by another name
+pub fn add_alias(characters: &mut [Character], id: usize, alias: &str) -> Result<(), String> {
+    check_name_free(characters, id, alias)?;
+    let chara = characters
+        .iter_mut()
+        .find(|c| c.id == id)
+        .ok_or_else(|| format!("no character #{}", id))?;
+    if !chara.names().any(|n| n == alias) {
+        chara.aliases.push(alias.to_string());
+    }
+    Ok(())
 }
@@ -0,0 +1,280 @@
+mod common;
+
+use common::new_pool;
+use glicko2::{
+    battle::{battles, Session},
+    matchmaker::{matchmaker_by_name, session_rng},
+    structs::{name_to_id_map, Character, Match, MatchResult},
+};
+
+// Run a seeded session driven by the script, returns the records, the transcript
+// and the journaled states of the session
+fn run_session(session: Session, script: &str) -> (Vec<Match>, String, Vec<Session>) {
+    let name_to_id = name_to_id_map(&session.pool);
+    let mut mm = matchmaker_by_name(&session.matchmaker).unwrap();
+    let mut out: Vec<u8> = Vec::new();
+    let mut journal: Vec<Session> = Vec::new();
+    let session = battles(
+        session,
+        &name_to_id,
+        mm.as_mut(),
+        &mut session_rng(Some(1)),
+        &mut script.as_bytes(),
+        &mut out,
+        &mut |s| journal.push(s.clone()),
+    );
+    (session.records, String::from_utf8(out).unwrap(), journal)
+}
+
+fn run(pool: &[Character], script: &str) -> (Vec<Match>, String) {
+    let (records, transcript, _) = run_session(Session::new(pool, "random"), script);
+    (records, transcript)
+}
+
+fn results(records: &[Match]) -> Vec<MatchResult> {
+    records.iter().map(|m| m.res.clone()).collect()
+}
+
+#[test]
+fn script_records_each_choice() {
+    let (records, transcript) = run(&new_pool(6), "11\n22\n0\nd\n\n");
+    assert_eq!(
+        results(&records),
+        [
+            MatchResult::AWin,
+            MatchResult::BWin,
+            MatchResult::Draw,
+            MatchResult::BothLose
+        ]
+    );
+    assert!(records.iter().all(|m| m.a != m.b));
+    assert!(transcript.starts_with("=== Starting a new session with 6 characters ==="));
+    assert_eq!(transcript.matches("Battle #").count(), 5);
+    assert!(transcript.ends_with("Finish rating session.\n"));
+}
+
+#[test]
+fn graded_choices() {
+    let (records, transcript) = run(&new_pool(6), "1\n2\n11\n22\n");
+    assert_eq!(
+        results(&records),
+        [
+            MatchResult::AWeakWin,
+            MatchResult::BWeakWin,
+            MatchResult::AWin,
+            MatchResult::BWin
+        ]
+    );
+    assert_eq!(transcript.matches("(slightly)!").count(), 2);
+}
+
+#[test]
+fn end_of_input_ends_the_session() {
+    let (records, transcript) = run(&new_pool(4), "1\n1");
+    assert_eq!(records.len(), 2);
+    assert!(transcript.ends_with("Finish rating session.\n"));
+}
+
+#[test]
+fn undo_drops_the_last_battle() {
+    let (records, transcript) = run(&new_pool(6), "11\n2\nu\n0\n");
+    assert_eq!(results(&records), [MatchResult::AWin, MatchResult::Draw]);
+    assert!(transcript.contains("Undoing..."));
+
+    // The battle after an undo is the undone one again
+    let battles: Vec<&str> = transcript
+        .lines()
+        .filter(|line| line.starts_with("Battle #"))
+        .collect();
+    assert_eq!(battles[1], battles[3]);
+}
+
+#[test]
+fn undo_and_help_before_the_first_battle() {
+    let (records, transcript) = run(&new_pool(4), "u\nh\n11\n");
+    assert_eq!(results(&records), [MatchResult::AWin]);
+    assert!(transcript.contains("This is the first battle!"));
+    assert!(transcript.contains("11/22 to choose left/right"));
+}
+
+#[test]
+fn same_seed_same_transcript() {
+    let pool = new_pool(8);
+    let script = "1\n2\n1\nd\n0\n2\n";
+    assert_eq!(run(&pool, script), run(&pool, script));
+}
+
+#[test]
+fn not_enough_characters() {
+    let (records, transcript) = run(&new_pool(1), "1\n");
+    assert!(records.is_empty());
+    assert!(transcript.contains("Not enough characters can battle"));
+}
+
+#[test]
+fn journal_after_every_pick() {
+    let (records, _, journal) = run_session(Session::new(&new_pool(6), "random"), "1\n2\nu\n");
+    // the first pair, two battles and the undo
+    assert_eq!(journal.len(), 4);
+    assert_eq!(journal[2].records.len(), 2);
+    assert_eq!(journal[3].records, records);
+    assert!(journal.iter().all(|s| s.pair.is_some()));
+    assert_eq!(journal[1].pair, journal[3].pair);
+}
+
+#[test]
+fn resume_a_journaled_session() {
+    let (_, _, journal) = run_session(Session::new(&new_pool(6), "random"), "1\n2\n0\n");
+    let interrupted = journal[2].clone();
+    let pending = interrupted.pair.unwrap();
+    let (a, b) = (
+        interrupted.pool[pending.0].id,
+        interrupted.pool[pending.1].id,
+    );
+
+    // A round trip through the journal file format keeps the session
+    let serialized = serde_json::to_string(&interrupted).unwrap();
+    let resumed: Session = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(resumed.pool[0].hist.draw, interrupted.pool[0].hist.draw);
+
+    let (records, transcript, _) = run_session(resumed, "d\n");
+    assert!(transcript.starts_with("=== Resuming a session after 2 battles ==="));
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[..2], journal[2].records[..]);
+    assert_eq!(records[2], Match::new(a, b, MatchResult::BothLose));
+}
+
+#[test]
+fn remap_follows_the_characters_by_id() {
+    let (_, _, journal) = run_session(Session::new(&new_pool(6), "random"), "1\n2\n");
+    let interrupted = journal[2].clone();
+    let pending = interrupted.pair.unwrap();
+    let id = |p: usize| interrupted.pool[p].id;
+    let pending_ids = (id(pending.0), id(pending.1));
+    let mut used: Vec<usize> = interrupted
+        .records
+        .iter()
+        .flat_map(|m| [m.a, m.b])
+        .collect();
+    used.extend([pending_ids.0, pending_ids.1]);
+
+    // Characters were added, reordered and one taking no part was removed
+    let mut characters = new_pool(8);
+    characters.reverse();
+    let unused = (0..6).find(|i| !used.contains(i)).unwrap();
+    characters.retain(|c| c.id != unused);
+    let remapped = interrupted.clone().remap(&characters).unwrap();
+    let ids: Vec<usize> = remapped.pool.iter().map(|c| c.id).collect();
+    let expected: Vec<usize> = characters.iter().map(|c| c.id).collect();
+    assert_eq!(ids, expected);
+    let pair = remapped.pair.unwrap();
+    assert_eq!((ids[pair.0], ids[pair.1]), pending_ids);
+    for old in interrupted.pool.iter().filter(|c| c.id != unused) {
+        let new = remapped.pool.iter().find(|c| c.id == old.id).unwrap();
+        assert_eq!(new.hist.wins, old.hist.wins);
+        assert_eq!(new.rank.devi, old.rank.devi);
+    }
+
+    let (records, _, _) = run_session(remapped, "0\n");
+    assert_eq!(records.len(), 3);
+    assert_eq!(
+        records[2],
+        Match::new(pending_ids.0, pending_ids.1, MatchResult::Draw)
+    );
+
+    // Without a character of a battle, the session cannot go on
+    let gone = interrupted.records[0].a;
+    characters.retain(|c| c.id != gone);
+    assert!(interrupted.remap(&characters).is_none());
+}
+
+fn pool_json(session: &Session) -> String {
+    serde_json::to_string(&session.pool).unwrap()
+}
+
+#[test]
+fn temporary_history_counts_real_outcomes() {
+    let (records, _, journal) = run_session(Session::new(&new_pool(6), "random"), "1\nd\n");
+    let pool = &journal[2].pool;
+    let hist = |id: usize| &pool.iter().find(|c| c.id == id).unwrap().hist;
+    assert_eq!(hist(records[0].a).wins, 1);
+    let total = |count: fn(&Character) -> usize| pool.iter().map(count).sum::<usize>();
+    assert_eq!(total(|c| c.hist.wins), 1);
+    assert_eq!(total(|c| c.hist.loss), 3);
+    assert_eq!(total(|c| c.hist.draw), 0);
+    assert_eq!(total(|c| c.hist.battles()), 4);
+}
+
+#[test]
+fn undo_restores_the_temporary_state_exactly() {
+    let (_, _, journal) = run_session(Session::new(&new_pool(6), "random"), "1\n2\nu\nu\n");
+    // journal: first pair, two battles, two undos
+    assert_eq!(pool_json(&journal[3]), pool_json(&journal[1]));
+    assert_eq!(pool_json(&journal[4]), pool_json(&journal[0]));
+    assert_eq!(journal[4].pair, journal[0].pair);
+}
+
+#[test]
+fn redo_replays_undone_battles() {
+    let (done, _, played) = run_session(Session::new(&new_pool(6), "random"), "1\n2\n0\n");
+    let (records, transcript, journal) = run_session(
+        Session::new(&new_pool(6), "random"),
+        "1\n2\n0\nu\nu\nu\nr\nr\nr\nr\n",
+    );
+    assert_eq!(records, done);
+    assert_eq!(transcript.matches("Redoing...").count(), 3);
+    assert!(transcript.contains("Nothing to redo!"));
+    let last = journal.last().unwrap();
+    assert_eq!(pool_json(last), pool_json(played.last().unwrap()));
+    assert_eq!(last.pair, played.last().unwrap().pair);
+}
+
+#[test]
+fn new_result_drops_the_redo() {
+    let (records, transcript, _) =
+        run_session(Session::new(&new_pool(6), "random"), "1\nu\n22\nr\n");
+    assert_eq!(results(&records), [MatchResult::BWin]);
+    assert!(transcript.contains("Nothing to redo!"));
+}
+
+#[test]
+fn skip_drops_the_redo() {
+    for skip in ["s", "sp", "s1", "s2"] {
+        let script = format!("1\nu\n{}\nr\n", skip);
+        let (records, transcript, _) = run_session(Session::new(&new_pool(6), "random"), &script);
+        assert!(records.is_empty(), "{}", skip);
+        assert!(transcript.contains("Nothing to redo!"), "{}", skip);
+    }
+}
+
+#[test]
+fn skip_records_nothing_and_picks_another_pair() {
+    let (records, transcript, journal) = run_session(Session::new(&new_pool(2), "random"), "s\n");
+    assert!(records.is_empty());
+    assert!(transcript.contains("Skipped!"));
+    // The only pair is skipped for the rest of the session
+    assert!(transcript.contains("Not enough characters can battle"));
+    assert!(journal[0].unknown_pairs.is_empty());
+}
+
+#[test]
+fn skip_marks_unknown_characters_and_pairs() {
+    let mut pool = new_pool(4);
+    let (_, _, journal) = run_session(Session::new(&pool, "random"), "s1\nsp\n");
+    let (first, second) = (journal[0].pair.unwrap(), journal[1].pair.unwrap());
+    let session = journal.last().unwrap();
+    assert_eq!(session.unknown_charas, [pool[first.0].id]);
+    assert_eq!(
+        session.unknown_pairs,
+        [(pool[second.0].id, pool[second.1].id)]
+    );
+    // The unknown character is never picked again in the session
+    assert!(journal[1..]
+        .iter()
+        .filter_map(|s| s.pair)
+        .all(|(a, b)| a != first.0 && b != first.0));
+
+    assert!(session.mark_unknown(&mut pool));
+    assert!(pool[first.0].unknown && !pool[first.0].excluded);
+    assert!(pool[second.0].avoids(&pool[second.1]));
+}
@@ -0,0 +1,145 @@
+mod common;
+
+use common::{run_cli, temp_pool};
+use glicko2::Character;
+use std::{fs, path::Path};
+
+#[test]
+fn replay_reproduces_a_live_run() {
+    let pool = temp_pool("replay", 8);
+    run_cli(&pool, &["play", "-", "--seed", "1"], "11\n2\n0\nd\n22\n1\n");
+    run_cli(&pool, &["play", "-", "--seed", "2"], "1\n1\n22\n");
+    run_cli(&pool, &["record", "c0", "c5", "b-weak-win"], "");
+    run_cli(&pool, &["play", "-", "--seed", "3"], "2\nd\n11\n0\n");
+    // Ratings pass through data.json between live sessions, enough sessions
+    // show any float which does not survive the round trip exactly
+    for seed in 4..20 {
+        let seed = seed.to_string();
+        run_cli(
+            &pool,
+            &["play", "-", "--seed", &seed],
+            "1\n22\n0\n2\n11\nd\n",
+        );
+    }
+    let live = fs::read_to_string(pool.join("data.json")).unwrap();
+
+    run_cli(&pool, &["replay"], "");
+    let replayed = fs::read_to_string(pool.join("data.json")).unwrap();
+    assert_eq!(live, replayed);
+    fs::remove_dir_all(&pool).unwrap();
+}
+
+#[test]
+fn play_from_stdin_gets_every_line_with_a_changed_roster() {
+    let pool = temp_pool("play-stdin", 6);
+    run_cli(&pool, &["record", "c0", "c1", "draw"], "");
+    // c5 is no longer in the roster, the loader must not ask about it
+    fs::write(pool.join("init.txt"), "c0\nc1\nc2\nc3\nc4\n").unwrap();
+
+    let transcript = run_cli(&pool, &["play", "-", "--seed", "1"], "11\n22\n\n");
+    assert!(transcript.starts_with("=== Starting a new session"));
+    let log = fs::read_to_string(pool.join("matches.jsonl")).unwrap();
+    let results: Vec<&str> = log
+        .lines()
+        .skip(1)
+        .map(|l| l.split("\"res\":").nth(1).unwrap())
+        .collect();
+    assert_eq!(results, ["\"AWin\"}", "\"BWin\"}"]);
+    fs::remove_dir_all(&pool).unwrap();
+}
+
+fn load(pool: &Path) -> Vec<Character> {
+    serde_json::from_str(&fs::read_to_string(pool.join("data.json")).unwrap()).unwrap()
+}
+
+#[test]
+fn known_takes_back_one_unknown_mark() {
+    let pool = temp_pool("known", 6);
+    run_cli(&pool, &["play", "-", "--seed", "1"], "s1\nsp\n\n");
+    let characters = load(&pool);
+    let unknown = characters.iter().find(|c| c.unknown).unwrap();
+    let avoiding = characters.iter().find(|c| !c.avoid.is_empty()).unwrap();
+    let (chara, pair) = (
+        unknown.name.clone(),
+        (avoiding.name.clone(), avoiding.avoid[0].to_string()),
+    );
+    assert!(characters.iter().all(|c| !c.excluded));
+    assert!(run_cli(&pool, &["list"], "").contains("  unknown"));
+    let stat = run_cli(&pool, &["stat", &pair.0], "");
+    assert!(stat.contains("unknown against: "));
+
+    // Including a character leaves its unknown marks alone
+    run_cli(&pool, &["include", &chara, &pair.0], "");
+    let characters = load(&pool);
+    assert_eq!(characters.iter().filter(|c| c.unknown).count(), 1);
+    assert_eq!(characters.iter().filter(|c| !c.avoid.is_empty()).count(), 1);
+
+    run_cli(&pool, &["known", &chara], "");
+    assert!(load(&pool).iter().all(|c| !c.unknown));
+    run_cli(&pool, &["known", &pair.1, &pair.0], "");
+    assert!(load(&pool).iter().all(|c| c.avoid.is_empty()));
+    fs::remove_dir_all(&pool).unwrap();
+}
+
+#[test]
+fn lobby_restores_a_broken_data_file_from_the_backup() {
+    let pool = temp_pool("recover", 4);
+    run_cli(&pool, &["record", "c0", "c1", "a-win"], "");
+    run_cli(&pool, &["record", "c2", "c3", "draw"], "");
+    let backup = fs::read_to_string(pool.join("data.json.1")).unwrap();
+    fs::write(pool.join("data.json"), "[{\"id\":").unwrap();
+
+    // Exit right after loading the backup, without any session
+    run_cli(&pool, &[], "1\nexit\n");
+    assert_eq!(fs::read_to_string(pool.join("data.json")).unwrap(), backup);
+    assert_eq!(
+        fs::read_to_string(pool.join("data.json.corrupt")).unwrap(),
+        "[{\"id\":"
+    );
+    assert_eq!(load(&pool)[0].hist.wins, 1);
+    fs::remove_dir_all(&pool).unwrap();
+}
+
+#[test]
+fn removed_characters_keep_their_ids() {
+    let pool = temp_pool("ids", 5);
+    run_cli(&pool, &["record", "c3", "c4", "draw"], "");
+    // c4 is gone from the data and the roster, c5 joins
+    let characters: Vec<Character> = load(&pool).into_iter().filter(|c| c.id != 4).collect();
+    fs::write(
+        pool.join("data.json"),
+        serde_json::to_string(&characters).unwrap(),
+    )
+    .unwrap();
+    fs::write(pool.join("init.txt"), "c0\nc1\nc2\nc3\nc5\n").unwrap();
+
+    run_cli(&pool, &["record", "c5", "c0", "a-win"], "");
+    let c5 = load(&pool).into_iter().find(|c| c.name == "c5").unwrap();
+    assert_eq!(c5.id, 5);
+    assert_eq!((c5.hist.wins, c5.hist.draw), (1, 0));
+    fs::remove_dir_all(&pool).unwrap();
+}
+
+#[test]
+fn lobby_keeps_the_roster_changes_without_a_session() {
+    let pool = temp_pool("roster", 4);
+    run_cli(&pool, &["record", "c0", "c1", "draw"], "");
+    fs::write(pool.join("init.txt"), "c0\nc1\nc2\nc4\n").unwrap();
+
+    // Remove c3 at the prompt and leave at once
+    run_cli(&pool, &[], "Y\nexit\n");
+    let names: Vec<String> = load(&pool).into_iter().map(|c| c.name).collect();
+    assert_eq!(names, ["c0", "c1", "c2", "c4"]);
+    fs::remove_dir_all(&pool).unwrap();
+}
+
+#[test]
+fn broken_snapshots_never_lose_a_session() {
+    let pool = temp_pool("snapshots", 2);
+    fs::write(pool.join("snapshots.jsonl"), "broken\n").unwrap();
+    run_cli(&pool, &["record", "c0", "c1", "a-win"], "");
+    assert_eq!(load(&pool)[0].hist.wins, 1);
+    let log = fs::read_to_string(pool.join("matches.jsonl")).unwrap();
+    assert_eq!(log.lines().count(), 1);
+    fs::remove_dir_all(&pool).unwrap();
+}
@@ -0,0 +1,55 @@
+// Fixtures shared by the integration tests, each test crate uses only some
+#![allow(dead_code)]
+
+use glicko2::{Character, GlickoConfig};
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{self, Command, Stdio},
+};
+
+// Characters c0, c1, ... with the ids 0, 1, ... and the initial rank
+pub fn new_pool(n: usize) -> Vec<Character> {
+    let config = GlickoConfig::default();
+    (0..n)
+        .map(|i| Character::new(i, format!("c{}", i), &config))
+        .collect()
+}
+
+// A fresh pool directory with the roster c0, c1, ... for running the binary
+pub fn temp_pool(name: &str, n: usize) -> PathBuf {
+    let dir = env::temp_dir().join(format!("glicko2-test-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let roster: String = (0..n).map(|i| format!("c{}\n", i)).collect();
+    fs::write(dir.join("init.txt"), roster).unwrap();
+    dir
+}
+
+// Run the binary on a pool with the given input, returns its stdout
+pub fn run_cli(pool: &Path, args: &[&str], input: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_glicko2"))
+        .args(args)
+        .arg("--pool")
+        .arg(pool)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
@@ -0,0 +1,187 @@
+mod common;
+
+use common::new_pool;
+use glicko2::{
+    calculate_ranking, calculate_results, config::GLICKO2_SCALE, replay, update_history, Character,
+    GlickoConfig, LogEntry, Match, MatchResult,
+};
+
+// The rating change of a and b after a single match
+fn rating_change(res: MatchResult, config: &GlickoConfig) -> (f64, f64) {
+    let mut characters = new_pool(2);
+    calculate_results(&mut characters, &[Match::new(0, 1, res)], config, 0);
+    (
+        characters[0].rank.rati - config.init_rati,
+        characters[1].rank.rati - config.init_rati,
+    )
+}
+
+#[test]
+fn weak_wins_move_ratings_less() {
+    let config = GlickoConfig::default();
+    let (strong_a, strong_b) = rating_change(MatchResult::AWin, &config);
+    let (weak_a, weak_b) = rating_change(MatchResult::AWeakWin, &config);
+    assert!(0.0 < weak_a && weak_a < strong_a);
+    assert!(strong_b < weak_b && weak_b < 0.0);
+
+    // Both sides see the same result
+    let (weak_b_a, weak_b_b) = rating_change(MatchResult::BWeakWin, &config);
+    assert!((weak_b_b - weak_a).abs() < 1e-9);
+    assert!((weak_b_a - weak_b).abs() < 1e-9);
+}
+
+#[test]
+fn weak_win_score_is_configurable() {
+    let mut config = GlickoConfig::default();
+    config.set("weak-win", "1").unwrap();
+    assert_eq!(
+        rating_change(MatchResult::AWeakWin, &config),
+        rating_change(MatchResult::AWin, &config)
+    );
+    config.set("weak-win", "0.5").unwrap();
+    let (a, b) = rating_change(MatchResult::AWeakWin, &config);
+    assert!(a.abs() < 1e-9 && b.abs() < 1e-9);
+    assert!(config.set("weak-win", "0.4").is_err());
+}
+
+// The deviation of c2 after a session between c0 and c1, c2 starting at devi
+fn idle_deviation(devi: f64, config: &GlickoConfig) -> Character {
+    let mut characters = new_pool(3);
+    characters[2].rank.devi = devi;
+    let records = [Match::new(0, 1, MatchResult::Draw)];
+    calculate_results(&mut characters, &records, config, 100);
+    characters.pop().unwrap()
+}
+
+#[test]
+fn idle_deviation_grows_up_to_the_initial_one() {
+    let config = GlickoConfig::default();
+    let idle = idle_deviation(100.0, &config);
+    assert!(idle.rank.devi > 100.0 && idle.rank.devi < config.init_devi);
+    assert_eq!(idle.rank.time, 100);
+    assert_eq!(idle_deviation(349.9, &config).rank.devi, config.init_devi);
+}
+
+#[test]
+fn idle_deviation_never_shrinks_to_the_cap() {
+    let mut config = GlickoConfig::default();
+    config.set("init-deviation", "200").unwrap();
+    assert!(idle_deviation(350.0, &config).rank.devi >= 350.0);
+}
+
+#[test]
+fn idle_deviation_stays_without_inflation() {
+    let mut config = GlickoConfig::default();
+    config.set("inflate-idle", "false").unwrap();
+    let idle = idle_deviation(100.0, &config);
+    assert_eq!(idle.rank.devi, 100.0);
+    assert_eq!(idle.rank.time, 0);
+}
+
+// The deviation after the periods of the inflation alone (Glicko-1 scale)
+fn inflated(devi: f64, vola: f64, periods: f64) -> f64 {
+    let phi = devi / GLICKO2_SCALE;
+    (phi * phi + periods * vola * vola).sqrt() * GLICKO2_SCALE
+}
+
+#[test]
+fn deviation_grows_with_fractional_periods() {
+    let mut config = GlickoConfig::default();
+    config.set("period-length", "100").unwrap();
+    let mut characters = new_pool(3);
+    characters[2].rank.devi = 100.0;
+    characters[2].rank.time = 1000;
+    let records = [Match::new(0, 1, MatchResult::Draw)];
+    calculate_results(&mut characters, &records, &config, 1050);
+    let expected = inflated(100.0, config.init_vola, 0.5);
+    assert!((characters[2].rank.devi - expected).abs() < 1e-9);
+}
+
+#[test]
+fn unknown_update_time_counts_one_period() {
+    let mut config = GlickoConfig::default();
+    config.set("period-length", "100").unwrap();
+    let idle = idle_deviation(100.0, &config);
+    assert_eq!(idle.rank.time, 100);
+    let expected = inflated(100.0, config.init_vola, 1.0);
+    assert!((idle.rank.devi - expected).abs() < 1e-9);
+}
+
+#[test]
+fn returning_players_never_exceed_the_initial_deviation() {
+    let mut config = GlickoConfig::default();
+    config.set("period-length", "1").unwrap();
+    config.set("inflate-idle", "false").unwrap();
+    let mut characters = new_pool(2);
+    for c in characters.iter_mut() {
+        c.rank.devi = 50.0;
+        c.rank.vola = 0.1;
+        c.rank.time = 1;
+    }
+    let records = [Match::new(0, 1, MatchResult::AWin)];
+    calculate_results(&mut characters, &records, &config, 100_000_000);
+    assert!(characters.iter().all(|c| c.rank.devi <= config.init_devi));
+}
+
+// Apply a session the way the binary does after the battles
+fn live_session(
+    characters: &mut [Character],
+    records: &[Match],
+    time: u64,
+    log: &mut Vec<LogEntry>,
+) {
+    let config = GlickoConfig::default();
+    let (_, ranks) = calculate_ranking(characters);
+    update_history(characters, records, &ranks);
+    calculate_results(characters, records, &config, time);
+    let session = log.last().map_or(0, |e| e.session + 1);
+    log.extend(records.iter().map(|m| LogEntry {
+        session,
+        time,
+        game: m.clone(),
+    }));
+}
+
+#[test]
+fn replay_of_a_growing_roster_matches_the_live_run() {
+    let mut log: Vec<LogEntry> = Vec::new();
+    let mut pool = new_pool(4);
+    let mut live = pool[..2].to_vec();
+    live_session(
+        &mut live,
+        &[Match::new(0, 1, MatchResult::AWin)],
+        10,
+        &mut log,
+    );
+    // c2 joins with a known time and only battles later, c3 comes from data
+    // written before join times were kept
+    pool[2].since = 20;
+    live.push(pool[2].clone());
+    live_session(
+        &mut live,
+        &[Match::new(0, 1, MatchResult::Draw)],
+        30,
+        &mut log,
+    );
+    live_session(
+        &mut live,
+        &[Match::new(1, 2, MatchResult::BWin)],
+        40,
+        &mut log,
+    );
+    live.push(pool[3].clone());
+    live_session(
+        &mut live,
+        &[Match::new(2, 3, MatchResult::AWeakWin)],
+        50,
+        &mut log,
+    );
+    assert_eq!(live[2].hist.old_rate.len(), 3);
+
+    let mut replayed = live.clone();
+    assert_eq!(replay(&mut replayed, &log, &GlickoConfig::default()), Ok(4));
+    assert_eq!(
+        serde_json::to_string(&replayed).unwrap(),
+        serde_json::to_string(&live).unwrap()
+    );
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::new_pool;
+use glicko2::{
+    matchmaker::{matchmaker_by_name, session_rng, MATCHMAKERS},
+    structs::{name_to_id_map, Battle, Character, MatchResult},
+};
+use std::collections::HashMap;
+
+const PICKS: usize = 50;
+
+fn meet(pool: &mut [Character], a: usize, b: usize) {
+    let (name_a, name_b) = (pool[a].name.clone(), pool[b].name.clone());
+    pool[a].hist.draw += 1;
+    pool[b].hist.draw += 1;
+    pool[a]
+        .hist
+        .recent
+        .push_back(Battle::new(name_b, MatchResult::Draw));
+    pool[b]
+        .hist
+        .recent
+        .push_back(Battle::new(name_a, MatchResult::Draw));
+}
+
+// Pick many pairs with every matchmaker and check each of them
+fn check_picks<F>(pool: &[Character], check: F)
+where
+    F: Fn(&str, Option<(usize, usize)>),
+{
+    let name_to_id: HashMap<String, usize> = name_to_id_map(pool);
+    let mut rng = session_rng(None);
+    for name in MATCHMAKERS {
+        let mut mm = matchmaker_by_name(name).unwrap();
+        for _ in 0..PICKS {
+            check(name, mm.pick(pool, &name_to_id, &mut rng));
+        }
+    }
+}
+
+// The pairs of a session where every picked pair battles before the next pick
+fn seeded_session(name: &str, seed: u64, n: usize) -> Vec<(usize, usize)> {
+    let mut pool = new_pool(8);
+    let name_to_id: HashMap<String, usize> = name_to_id_map(&pool);
+    let mut rng = session_rng(Some(seed));
+    let mut mm = matchmaker_by_name(name).unwrap();
+    let mut pairs = Vec::new();
+    for _ in 0..n {
+        let (a, b) = mm.pick(&pool, &name_to_id, &mut rng).unwrap();
+        meet(&mut pool, a, b);
+        pairs.push((a, b));
+    }
+    pairs
+}
+
+fn assert_valid(pool: &[Character], name: &str, pair: Option<(usize, usize)>) {
+    let (a, b) = pair.unwrap_or_else(|| panic!("{}: no pair", name));
+    assert_ne!(a, b, "{}: picked the same character", name);
+    assert!(pool[a].matchable() && pool[b].matchable(), "{}", name);
+}
+
+#[test]
+fn pool_of_two_with_equal_counts() {
+    let pool = new_pool(2);
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn pool_of_two_after_meeting() {
+    let mut pool = new_pool(2);
+    meet(&mut pool, 0, 1);
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn pool_of_three_all_recent_opponents() {
+    let mut pool = new_pool(3);
+    meet(&mut pool, 0, 1);
+    meet(&mut pool, 1, 2);
+    meet(&mut pool, 2, 0);
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn pool_of_three_uneven_counts() {
+    let mut pool = new_pool(3);
+    meet(&mut pool, 0, 1);
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn all_equal_counts() {
+    let mut pool = new_pool(10);
+    for c in pool.iter_mut() {
+        c.hist.wins = 3;
+    }
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn large_count_differences() {
+    let mut pool = new_pool(4);
+    pool[0].hist.wins = 1000;
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn skips_excluded_characters() {
+    let mut pool = new_pool(5);
+    pool[1].excluded = true;
+    pool[3].meta.active = false;
+    check_picks(&pool, |name, pair| assert_valid(&pool, name, pair));
+}
+
+#[test]
+fn never_picks_avoided_pairs() {
+    let mut pool = new_pool(4);
+    // Only 0 - 3 and 1 - 2 are left
+    pool[0].avoid = vec![1, 2];
+    pool[3].avoid = vec![1, 2];
+    check_picks(&pool, |name, pair| {
+        assert_valid(&pool, name, pair);
+        let (a, b) = pair.unwrap();
+        assert!(!pool[a].avoids(&pool[b]), "{}", name);
+    });
+}
+
+#[test]
+fn too_few_characters() {
+    let mut pool = new_pool(3);
+    pool[0].excluded = true;
+    pool[2].excluded = true;
+    check_picks(&pool, |name, pair| assert!(pair.is_none(), "{}", name));
+    check_picks(&new_pool(1), |name, pair| {
+        assert!(pair.is_none(), "{}", name)
+    });
+    check_picks(&new_pool(0), |name, pair| {
+        assert!(pair.is_none(), "{}", name)
+    });
+    let mut pool = new_pool(2);
+    pool[1].avoid.push(0);
+    check_picks(&pool, |name, pair| assert!(pair.is_none(), "{}", name));
+}
+
+#[test]
+fn same_seed_same_session() {
+    for name in MATCHMAKERS {
+        assert_eq!(
+            seeded_session(name, 7, PICKS),
+            seeded_session(name, 7, PICKS),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn seeded_session_golden() {
+    // A change here means recorded seeds no longer replay the same sessions
+    assert_eq!(
+        seeded_session("random", 2024, 6),
+        [(6, 1), (5, 4), (3, 7), (0, 4), (2, 7), (5, 1)]
+    );
+    assert_eq!(
+        seeded_session("weighted", 2024, 6),
+        [(6, 1), (7, 4), (5, 3), (0, 2), (4, 5), (1, 2)]
+    );
+}
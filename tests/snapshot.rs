@@ -0,0 +1,48 @@
+mod common;
+
+use common::new_pool;
+use glicko2::{
+    calculate_ranking, calculate_results, update_history, GlickoConfig, Match, MatchResult,
+    Snapshot,
+};
+
+#[test]
+fn restore_undoes_a_session_exactly() {
+    let config = GlickoConfig::default();
+    let mut characters = new_pool(4);
+    let records = [
+        Match::new(0, 1, MatchResult::AWin),
+        Match::new(2, 3, MatchResult::Draw),
+    ];
+    let (_, ranks) = calculate_ranking(&characters);
+    update_history(&mut characters, &records, &ranks);
+    calculate_results(&mut characters, &records, &config, 100);
+    let before = serde_json::to_string(&characters).unwrap();
+
+    // Take the snapshot through the file format, as the lobby does
+    let snapshot = Snapshot::take(1, 200, 1, &characters);
+    let snapshot: Snapshot =
+        serde_json::from_str(&serde_json::to_string(&snapshot).unwrap()).unwrap();
+
+    let records = [Match::new(1, 2, MatchResult::BWin)];
+    let (_, ranks) = calculate_ranking(&characters);
+    update_history(&mut characters, &records, &ranks);
+    calculate_results(&mut characters, &records, &config, 200);
+    assert_ne!(serde_json::to_string(&characters).unwrap(), before);
+
+    snapshot.restore(&mut characters);
+    assert_eq!(serde_json::to_string(&characters).unwrap(), before);
+}
+
+#[test]
+fn restore_takes_back_unknown_marks() {
+    let mut characters = new_pool(3);
+    characters[0].avoid.push(2);
+    let snapshot = Snapshot::take(0, 100, 1, &characters);
+    characters[1].unknown = true;
+    characters[0].avoid.push(1);
+
+    snapshot.restore(&mut characters);
+    assert!(!characters[1].unknown);
+    assert_eq!(characters[0].avoid, [2]);
+}